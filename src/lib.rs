@@ -1,28 +1,227 @@
 #![no_std]
 
-use soroban_auth::{Identifier, Signature};
-use soroban_sdk::{contractimpl, contracttype, log, vec, BytesN, Env, Vec};
+use soroban_auth::{verify, Identifier, Signature};
+use soroban_sdk::{
+    contracterror, contractimpl, contracttype, log, panic_with_error, symbol, vec, BytesN, Env, Vec,
+};
 
 mod token {
     soroban_sdk::contractimport!(file = "../soroban_token_spec.wasm");
 }
 
+mod strategy {
+    use soroban_sdk::{contractclient, Env};
+
+    // minimal interface the vault expects from a strategy contract
+    #[contractclient(name = "Client")]
+    pub trait StrategyTrait {
+        // `amount` of the vault's token has been transferred to the strategy
+        fn deposit(e: Env, amount: i128);
+
+        // sends `amount` of the vault's token back to the invoker
+        fn withdraw(e: Env, amount: i128);
+
+        // amount of the vault's token the strategy holds for the vault
+        fn balance(e: Env) -> i128;
+
+        // sends the yield accrued since the last harvest to the invoker and returns its amount
+        fn harvest(e: Env) -> i128;
+
+        // yield accrued since the last harvest
+        fn accrued(e: Env) -> i128;
+
+        // part of the balance the strategy can send back right now
+        fn available(e: Env) -> i128;
+    }
+}
+
+mod converter {
+    use soroban_sdk::{contractclient, Env};
+
+    // turns the vault's deposit token into the token it accounts in
+    #[contractclient(name = "Client")]
+    pub trait ConverterTrait {
+        // `amount` of the deposit token has been transferred to the converter,
+        // sends its value in the vault's token to the invoker
+        fn convert(e: Env, amount: i128);
+    }
+}
+
+// codes the vault fails with, so callers can tell the failures apart
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
+pub enum VaultError {
+    NotAuthorized = 1,
+    InvalidNonce = 2,
+    InsufficientShares = 3,
+    Paused = 4,
+    NotInitialized = 5,
+    AlreadyInitialized = 6,
+    InvalidAmount = 7,
+    InvalidBps = 8,
+    InvalidToken = 9,
+    InvalidAdmin = 10,
+    NoWithdrawRequest = 11,
+    CooldownActive = 12,
+    NotAllowlisted = 13,
+    WithdrawLimit = 14,
+    BelowMinDeposit = 15,
+    InvalidDepositUnit = 16,
+    FractionalShares = 17,
+    ZeroShares = 18,
+    InsufficientLiquidity = 19,
+    BatchNotFound = 20,
+    InsufficientAllowance = 21,
+    NoStrategy = 22,
+    BatchTooLarge = 23,
+    InvalidSnapshot = 24,
+    NoShares = 25,
+    NoPendingAdmin = 26,
+    NothingToClaim = 27,
+    Overflow = 28,
+    DivByZero = 29,
+    PriceDrop = 30,
+    NotFound = 31,
+}
+
 #[derive(Clone)]
 #[contracttype]
 pub enum DataKey {
     TokenId,
     Admin,
     TotSupply,
-    InitialDep(Identifier),
+    TotDeposit,
+    WholeShrs,
+    Strategy,
+    PerfFee,
+    AllowList,
+    MinDeposit,
+    DepUnit,
+    MaxWithd,
+    Cooldown,
     Nonce(Identifier),
     Batch(BatchKey),
     Batches(Identifier),
+    Allowed(Identifier),
+    WithdReq(Identifier),
+    PriceHist,
+    Role(Identifier),
+    DepToken,
+    Converter,
+    Allowance(AllowKey),
+    AllowExp,
+    SnapId,
+    Snapshot(SnapKey),
+    SnapIds(Identifier),
+    RwdTokens,
+    RwdIndex(BytesN<32>),
+    RwdDebt(RwdKey),
+    RwdOwed(RwdKey),
+    LastPrice(Identifier),
+    PendAdmin,
+    Tracked,
+    WithdQueue,
+    Claim(Identifier),
+    Reserved,
+    Matured,
+    RefVolume(BytesN<32>),
+    VOffset,
+    DepPaused,
+    BufferBps,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[contracttype]
+pub enum Role {
+    // every admin action, implies Operator
+    Admin,
+    // day to day operations (harvesting)
+    Operator,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct Auth {
+    pub sig: Signature,
+    pub nonce: i128,
 }
 
 #[derive(Clone)]
 #[contracttype]
 pub struct BatchKey(pub Identifier, pub u64);
 
+// (owner, spender)
+#[derive(Clone)]
+#[contracttype]
+pub struct AllowKey(pub Identifier, pub Identifier);
+
+// (snapshot id, holder)
+#[derive(Clone)]
+#[contracttype]
+pub struct SnapKey(pub u32, pub Identifier);
+
+// (reward token, holder)
+#[derive(Clone)]
+#[contracttype]
+pub struct RwdKey(pub BytesN<32>, pub Identifier);
+
+#[derive(Clone)]
+#[contracttype]
+pub struct VaultConfig {
+    pub perf_fee: u32,
+    pub whole_shrs: bool,
+    pub allowlist: bool,
+    pub max_withd: u32,
+    pub min_dep: i128,
+    pub cooldown: u64,
+    pub dep_unit: i128,
+    pub buffer_bps: u32,
+    pub dep_paused: bool,
+}
+
+#[derive(Clone)]
+#[contracttype]
+pub struct WithdSim {
+    pub deposits: i128,
+    pub fees: i128,
+    pub payout: i128,
+    pub recall: i128,
+    // whether withdraw would pay out right now
+    pub instant: bool,
+    // VaultError code withdraw would fail with, 0 if none. Only short liquidity can be
+    // worked around, by queueing the withdrawal with queue_wd
+    pub blocked: u32,
+}
+
+// (fees already held by the admin as shares, performance fee on yield not harvested yet)
+#[derive(Clone)]
+#[contracttype]
+pub struct Earnings(pub i128, pub i128);
+
+// (total assets, assets needed to pay back every recorded deposit), solvent while the first covers the second
+#[derive(Clone)]
+#[contracttype]
+pub struct Solvency(pub i128, pub i128);
+
+// (total assets, total supply, price per share scaled by 10^7, performance fee bps)
+#[derive(Clone)]
+#[contracttype]
+pub struct Quote(pub i128, pub i128, pub i128, pub u32);
+
+// (assets waiting in the withdraw queue, assets set aside and ready to be claimed)
+#[derive(Clone)]
+#[contracttype]
+pub struct WithdClaim(pub i128, pub i128);
+
+#[derive(Clone)]
+#[contracttype]
+pub struct DepEntry(pub Identifier, pub i128);
+
+#[derive(Clone)]
+#[contracttype]
+pub struct PricePt(pub u64, pub i128);
+
 #[derive(Clone)]
 #[contracttype]
 pub struct BatchObj {
@@ -31,6 +230,46 @@ pub struct BatchObj {
     curr_s: i128,
 }
 
+// prices are scaled by PRICE_SCALE, only the latest MAX_PRICE_PTS checkpoints are kept
+const PRICE_SCALE: i128 = 10000000;
+const MAX_PRICE_PTS: u32 = 30;
+
+const SECONDS_PER_YEAR: i128 = 31536000;
+
+// rewards per share are scaled by REWARD_SCALE
+const REWARD_SCALE: i128 = 1000000000000;
+
+// entries accepted by a single dep_batch or shares_of call
+const MAX_BATCH: u32 = 50;
+
+// virtual shares and assets every vault starts with, see get_offset
+const VIRTUAL_OFFSET: i128 = 1;
+
+// a * b / c rounded down, rejecting the operation instead of wrapping around on overflow
+fn mul_div_floor(e: &Env, a: i128, b: i128, c: i128) -> i128 {
+    if c == 0 {
+        panic_with_error!(e, VaultError::DivByZero)
+    }
+
+    a.checked_mul(b)
+        .and_then(|product| product.checked_div_euclid(c))
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::Overflow))
+}
+
+// a * b / c rounded up
+fn mul_div_ceil(e: &Env, a: i128, b: i128, c: i128) -> i128 {
+    let floor = mul_div_floor(e, a, b, c);
+    let product = a
+        .checked_mul(b)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::Overflow));
+
+    if product.checked_rem_euclid(c) == Some(0) {
+        floor
+    } else {
+        floor + 1
+    }
+}
+
 fn get_contract_id(e: &Env) -> Identifier {
     Identifier::Contract(e.get_current_contract())
 }
@@ -45,277 +284,2458 @@ fn get_tot_supply(e: &Env) -> i128 {
     e.storage().get(key).unwrap_or(Ok(0)).unwrap()
 }
 
-fn put_token_id(e: &Env, token_id: BytesN<32>) {
-    let key = DataKey::TokenId;
-    e.storage().set(key, token_id);
+fn put_tot_deposit(e: &Env, deposit: i128) {
+    let key = DataKey::TotDeposit;
+    e.storage().set(key, deposit);
 }
 
-fn get_token_id(e: &Env) -> BytesN<32> {
-    let key = DataKey::TokenId;
-    e.storage().get(key).unwrap().unwrap()
+fn get_tot_deposit(e: &Env) -> i128 {
+    let key = DataKey::TotDeposit;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
 }
 
-fn get_token_balance(e: &Env) -> i128 {
-    let contract_id = get_token_id(e);
-    token::Client::new(e, contract_id).balance(&get_contract_id(e))
+fn put_whole_shares(e: &Env, enabled: bool) {
+    let key = DataKey::WholeShrs;
+    e.storage().set(key, enabled);
 }
 
-fn transfer(e: &Env, to: &Identifier, amount: i128) {
-    let client = token::Client::new(e, get_token_id(e));
-    client.xfer(
-        &Signature::Invoker,
-        &client.nonce(&Signature::Invoker.identifier(e)),
-        to,
-        &amount,
-    );
+fn get_whole_shares(e: &Env) -> bool {
+    let key = DataKey::WholeShrs;
+    e.storage().get(key).unwrap_or(Ok(false)).unwrap()
 }
 
-fn transfer_in_vault(e: &Env, from: &Identifier, amount: &i128) {
-    let client = token::Client::new(e, get_token_id(e));
-    let vault_id = get_contract_id(e);
+fn put_deposits_paused(e: &Env, paused: bool) {
+    let key = DataKey::DepPaused;
+    e.storage().set(key, paused);
+}
 
-    client.xfer_from(&Signature::Invoker, &0, from, &vault_id, amount);
+fn get_deposits_paused(e: &Env) -> bool {
+    let key = DataKey::DepPaused;
+    e.storage().get(key).unwrap_or(Ok(false)).unwrap()
 }
 
-fn has_administrator(e: &Env) -> bool {
-    let key = DataKey::Admin;
+fn check_deposits_open(e: &Env) {
+    if get_deposits_paused(e) {
+        panic_with_error!(e, VaultError::Paused)
+    }
+}
+
+fn has_strategy(e: &Env) -> bool {
+    let key = DataKey::Strategy;
     e.storage().has(key)
 }
 
-fn read_administrator(e: &Env) -> Identifier {
-    let key = DataKey::Admin;
-    e.storage().get_unchecked(key).unwrap()
+fn put_strategy(e: &Env, strategy: BytesN<32>) {
+    let key = DataKey::Strategy;
+    e.storage().set(key, strategy);
 }
 
-fn write_administrator(e: &Env, id: Identifier) {
-    let key = DataKey::Admin;
-    e.storage().set(key, id);
+fn get_strategy(e: &Env) -> BytesN<32> {
+    let key = DataKey::Strategy;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::NoStrategy))
+        .unwrap()
 }
 
-fn read_nonce(e: &Env, id: &Identifier) -> i128 {
-    let key = DataKey::Nonce(id.clone());
-    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+fn put_perf_fee(e: &Env, bps: u32) {
+    let key = DataKey::PerfFee;
+    e.storage().set(key, bps);
 }
 
-fn mint_shares(e: &Env, to: Identifier, shares: i128, deposit: i128) -> u64 {
-    let tot_supply = get_tot_supply(e);
-    put_tot_supply(e, tot_supply + shares);
+fn get_perf_fee(e: &Env) -> u32 {
+    let key = DataKey::PerfFee;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-    let ts = e.ledger().timestamp();
-    let key = DataKey::Batch(BatchKey(to.clone(), ts));
+fn put_allowlist(e: &Env, enabled: bool) {
+    let key = DataKey::AllowList;
+    e.storage().set(key, enabled);
+}
 
-    let val = BatchObj {
-        init_s: shares,
-        deposit,
-        curr_s: shares,
-    };
+fn get_allowlist(e: &Env) -> bool {
+    let key = DataKey::AllowList;
+    e.storage().get(key).unwrap_or(Ok(false)).unwrap()
+}
 
-    add_user_batch(e, to, ts);
-    e.storage().set(key, val);
+// allowlist entries hold the timestamp they expire at, u64::MAX for entries that don't
+fn read_allowed(e: &Env, id: Identifier) -> Option<u64> {
+    let key = DataKey::Allowed(id);
+    e.storage().get(key).map(|expires| expires.unwrap())
+}
 
-    ts
+fn is_allowed(e: &Env, id: Identifier) -> bool {
+    match read_allowed(e, id) {
+        Some(expires) => e.ledger().timestamp() < expires,
+        None => false,
+    }
 }
 
-fn get_user_batches(e: &Env, id: Identifier) -> Vec<u64> {
-    let key = DataKey::Batches(id);
+// ids allowed with an expiry, the ones prune_exp goes through
+fn get_expiring(e: &Env) -> Vec<Identifier> {
+    let key = DataKey::AllowExp;
     e.storage()
         .get(key)
         .unwrap_or_else(|| Ok(Vec::new(e)))
         .unwrap()
 }
 
-fn add_user_batch(e: &Env, id: Identifier, batch_ts: u64) {
-    let mut batches = get_user_batches(e, id.clone());
-    batches.push_front(batch_ts);
-
-    let key = DataKey::Batches(id);
-    e.storage().set(key, batches);
+fn check_bps(e: &Env, bps: u32) {
+    if bps > 10000 {
+        panic_with_error!(e, VaultError::InvalidBps)
+    }
 }
 
-fn remove_user_batch(e: &Env, id: Identifier, batch_ts: u64) {
-    let mut batches = get_user_batches(e, id.clone());
-    let batch_idx = batches.iter().position(|x| x.unwrap() == batch_ts).unwrap();
-
-    batches.remove(batch_idx as u32);
-
-    let key = DataKey::Batches(id);
-    e.storage().set(key, batches);
+fn put_min_deposit(e: &Env, amount: i128) {
+    let key = DataKey::MinDeposit;
+    e.storage().set(key, amount);
 }
 
-fn burn_shares(e: &Env, to: Identifier, shares: i128, batch_ts: u64) {
-    let tot_supply = get_tot_supply(e);
-    let key = DataKey::Batch(BatchKey(to.clone(), batch_ts));
+fn get_min_deposit(e: &Env) -> i128 {
+    let key = DataKey::MinDeposit;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-    let mut batch: BatchObj = e.storage().get(key.clone()).unwrap().unwrap();
-    batch.curr_s -= shares;
-    put_tot_supply(e, tot_supply - shares);
+fn put_deposit_unit(e: &Env, unit: i128) {
+    let key = DataKey::DepUnit;
+    e.storage().set(key, unit);
+}
 
-    if batch.curr_s == 0 {
-        e.storage().remove(key); // if there are 0 shares remove the batch
-        remove_user_batch(e, to, batch_ts);
-    } else {
-        e.storage().set(key, batch);
+fn check_deposit_unit(e: &Env, unit: i128) {
+    if unit <= 0 {
+        panic_with_error!(e, VaultError::InvalidDepositUnit)
     }
 }
 
-pub trait VaultContractTrait {
-    // Sets the admin and the vault's token id
-    fn initialize(e: Env, admin: Identifier, token_id: BytesN<32>);
+fn get_deposit_unit(e: &Env) -> i128 {
+    let key = DataKey::DepUnit;
+    e.storage().get(key).unwrap_or(Ok(1)).unwrap()
+}
 
-    // Returns the nonce for the admin
-    fn nonce(e: Env) -> i128;
+fn put_max_withdraw(e: &Env, bps: u32) {
+    let key = DataKey::MaxWithd;
+    e.storage().set(key, bps);
+}
 
-    // deposit shares into the vault: mints the vault shares to "from"
-    fn deposit(e: Env, from: Identifier, amount: i128) -> u64;
+fn get_max_withdraw(e: &Env) -> u32 {
+    let key = DataKey::MaxWithd;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-    /// withdraw fees
-    fn fee_withd(e: Env, to: Identifier, batch_ts: u64, shares: i128);
+fn put_buffer_bps(e: &Env, bps: u32) {
+    let key = DataKey::BufferBps;
+    e.storage().set(key, bps);
+}
 
-    // get vault shares for a user
-    fn get_shares(e: Env, id: Identifier, batch_ts: u64) -> BatchObj;
+fn get_buffer_bps(e: &Env) -> u32 {
+    let key = DataKey::BufferBps;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-    fn batches(e: Env, id: Identifier) -> Vec<u64>;
+// assets the vault accounts for: deposits and harvested yield in, payouts out.
+// Anything else showing up in the total assets is picked up by reconcile
+fn get_tracked(e: &Env) -> i128 {
+    let key = DataKey::Tracked;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-    fn withdraw(e: Env, to: Identifier) -> i128;
+fn put_tracked(e: &Env, amount: i128) {
+    let key = DataKey::Tracked;
+    e.storage().set(key, amount);
 }
 
-pub struct VaultContract;
+fn add_tracked(e: &Env, amount: i128) {
+    put_tracked(e, get_tracked(e) + amount);
+}
 
-#[contractimpl]
-impl VaultContractTrait for VaultContract {
-    fn initialize(e: Env, admin: Identifier, token_id: BytesN<32>) {
-        log!(&e, "initializing");
+fn put_cooldown(e: &Env, seconds: u64) {
+    let key = DataKey::Cooldown;
+    e.storage().set(key, seconds);
+}
 
-        if has_administrator(&e) {
-            panic!("admin is already set");
-        }
+fn get_cooldown(e: &Env) -> u64 {
+    let key = DataKey::Cooldown;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-        write_administrator(&e, admin);
+fn put_token_id(e: &Env, token_id: BytesN<32>) {
+    let key = DataKey::TokenId;
+    e.storage().set(key, token_id);
+}
 
-        put_token_id(&e, token_id)
-    }
+fn get_token_id(e: &Env) -> BytesN<32> {
+    let key = DataKey::TokenId;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::NotInitialized))
+        .unwrap()
+}
 
-    fn nonce(e: Env) -> i128 {
-        read_nonce(&e, &read_administrator(&e))
-    }
+// deposits are taken in the vault's token unless a deposit token and its converter are set
+fn has_converter(e: &Env) -> bool {
+    let key = DataKey::Converter;
+    e.storage().has(key)
+}
 
-    fn deposit(e: Env, from: Identifier, amount: i128) -> u64 {
-        log!(&e, "depositing");
-        transfer_in_vault(&e, &from, &amount);
+fn put_converter(e: &Env, token_id: BytesN<32>, converter: BytesN<32>) {
+    e.storage().set(DataKey::DepToken, token_id);
+    e.storage().set(DataKey::Converter, converter);
+}
 
-        let tot_supply = get_tot_supply(&e);
+fn remove_converter(e: &Env) {
+    e.storage().remove(DataKey::DepToken);
+    e.storage().remove(DataKey::Converter);
+}
 
-        let shares = if 0 == tot_supply {
-            amount
-        } else {
-            (amount * tot_supply) / (get_token_balance(&e) - amount)
-        };
+fn get_converter(e: &Env) -> (BytesN<32>, BytesN<32>) {
+    let token_id = e
+        .storage()
+        .get(DataKey::DepToken)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::NotFound))
+        .unwrap();
+    let converter = e
+        .storage()
+        .get(DataKey::Converter)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::NotFound))
+        .unwrap();
+    (token_id, converter)
+}
 
-        e.storage().set(DataKey::InitialDep(from.clone()), amount);
-        mint_shares(&e, from, shares, amount)
+fn get_deposit_token(e: &Env) -> BytesN<32> {
+    if has_converter(e) {
+        get_converter(e).0
+    } else {
+        get_token_id(e)
     }
+}
 
-    fn get_shares(e: Env, id: Identifier, batch_ts: u64) -> BatchObj {
-        let key = DataKey::Batch(BatchKey(id, batch_ts));
-
-        let batch: BatchObj = e.storage().get(key).unwrap().unwrap();
-
-        batch
-    }
+fn get_token_balance(e: &Env) -> i128 {
+    let contract_id = get_token_id(e);
+    token::Client::new(e, contract_id).balance(&get_contract_id(e))
+}
 
-    fn batches(e: Env, id: Identifier) -> Vec<u64> {
-        get_user_batches(&e, id)
+fn get_strategy_balance(e: &Env) -> i128 {
+    if !has_strategy(e) {
+        return 0;
     }
 
-    fn fee_withd(e: Env, to: Identifier, batch_ts: u64, shares: i128) {
-        let tot_supply = get_tot_supply(&e);
-        let tot_bal = get_token_balance(&e);
-        let batch: BatchObj = e
-            .storage()
-            .get(DataKey::Batch(BatchKey(to.clone(), batch_ts)))
-            .unwrap()
-            .unwrap();
-        let deposit = batch.deposit;
-        let init_s = batch.init_s;
-        let curr_s = batch.curr_s;
-
-        if curr_s < shares {
-            panic!("not enough shares");
-        }
+    strategy::Client::new(e, get_strategy(e)).balance()
+}
 
-        let new_deposit = deposit * (shares * 10000000 / init_s) / 10000000;
+// assets owed to queued withdrawals, `matured` is the part already set aside in the vault
+fn get_reserved(e: &Env) -> i128 {
+    let key = DataKey::Reserved;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-        let fee_amount = ((tot_bal * shares) / tot_supply) - new_deposit;
-        if fee_amount >= 0 {
-            transfer(&e, &to, fee_amount);
-            burn_shares(&e, to.clone(), shares, batch_ts);
-            let new_tot_supply = get_tot_supply(&e);
-            let new_tot_bal = get_token_balance(&e);
+fn get_matured(e: &Env) -> i128 {
+    let key = DataKey::Matured;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-            //        if curr_s != shares {
+fn add_reserved(e: &Env, reserved: i128, matured: i128) {
+    e.storage()
+        .set(DataKey::Reserved, get_reserved(e) + reserved);
+    e.storage().set(DataKey::Matured, get_matured(e) + matured);
+}
 
-            if tot_bal != new_deposit {
-                let new_shares = (new_deposit * new_tot_supply) / (new_tot_bal - new_deposit);
-                mint_shares(&e, to, new_shares, new_deposit);
-            } else {
-                let new_shares = (new_deposit * tot_supply) / new_deposit;
-                mint_shares(&e, to, new_shares, new_deposit);
-            }
-        }
+// tokens held by the vault that aren't set aside for matured withdraw claims
+fn get_idle_balance(e: &Env) -> i128 {
+    get_token_balance(e) - get_matured(e)
+}
 
-        //log!(&e, "new dep: {}, new shares:", new_deposit.clone(),);
-    }
+// assets backing the vault's shares, all the share math goes through here.
+// What's owed to queued withdrawals doesn't back any share anymore
+fn get_total_assets(e: &Env) -> i128 {
+    get_token_balance(e) + get_strategy_balance(e) - get_reserved(e)
+}
 
-    fn withdraw(e: Env, to: Identifier) -> i128 {
-        let batches = get_user_batches(&e, to.clone());
-        log!(&e, "batches {}", batches.clone());
+// virtual shares and assets added to both sides of the share math once there are shares. A
+// donation then partly accrues to the virtual shares, making inflating the price a loss for
+// whoever tries it. Set to VIRTUAL_OFFSET at initialization and never changed
+fn put_offset(e: &Env, offset: i128) {
+    let key = DataKey::VOffset;
+    e.storage().set(key, offset);
+}
 
-        let mut amount: i128 = 0;
-        let mut temp_supply: i128 = get_tot_supply(&e);
-        let mut temp_balance: i128 = get_token_balance(&e);
+fn get_offset(e: &Env) -> i128 {
+    let key = DataKey::VOffset;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
 
-        for batch_el in batches.iter() {
-            let batch_ts = batch_el.unwrap_or_else(|_| panic!("no ts in batch"));
+fn shares_for_amount(e: &Env, amount: i128) -> i128 {
+    let tot_supply = get_tot_supply(e);
+    if 0 == tot_supply {
+        amount
+    } else {
+        let offset = get_offset(e);
+        mul_div_floor(e, amount, tot_supply + offset, get_total_assets(e) + offset)
+    }
+}
 
-            let key = DataKey::Batch(BatchKey(to.clone(), batch_ts));
-            let batch: BatchObj = e
-                .storage()
-                .get(key.clone())
-                .unwrap_or_else(|| panic!("no batch with this id"))
-                .unwrap();
+fn amount_for_shares(e: &Env, shares: i128) -> i128 {
+    let tot_supply = get_tot_supply(e);
+    if 0 == tot_supply {
+        shares
+    } else {
+        let offset = get_offset(e);
+        mul_div_floor(e, shares, get_total_assets(e) + offset, tot_supply + offset)
+    }
+}
+
+fn get_price_hist(e: &Env) -> Vec<PricePt> {
+    let key = DataKey::PriceHist;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| Ok(Vec::new(e)))
+        .unwrap()
+}
+
+// price per share scaled by PRICE_SCALE, shares are minted 1:1 while there are none
+fn share_price(e: &Env) -> i128 {
+    amount_for_shares(e, PRICE_SCALE)
+}
+
+// records the current price per share, replacing a checkpoint taken in the same ledger
+fn checkpoint_price(e: &Env) {
+    if 0 == get_tot_supply(e) {
+        return;
+    }
+
+    let ts = e.ledger().timestamp();
+    let price = share_price(e);
+
+    let mut hist = get_price_hist(e);
+    if let Some(Ok(PricePt(last_ts, _))) = hist.last() {
+        if last_ts == ts {
+            hist.pop_back();
+        }
+    }
+
+    hist.push_back(PricePt(ts, price));
+    if hist.len() > MAX_PRICE_PTS {
+        hist.pop_front();
+    }
+
+    let key = DataKey::PriceHist;
+    e.storage().set(key, hist);
+}
+
+// forwards up to `amount` to the strategy, if there is one, keeping the buffer share of the total
+// assets idle
+fn deploy_to_strategy(e: &Env, amount: i128) {
+    if !has_strategy(e) {
+        return;
+    }
+
+    let buffer = mul_div_floor(e, get_total_assets(e), get_buffer_bps(e) as i128, 10000);
+    let excess = get_idle_balance(e) - buffer;
+    let amount = if excess < amount { excess } else { amount };
+    if amount <= 0 {
+        return;
+    }
+
+    let strategy_id = get_strategy(e);
+    transfer_token(
+        e,
+        get_token_id(e),
+        &Identifier::Contract(strategy_id.clone()),
+        amount,
+    );
+    strategy::Client::new(e, strategy_id).deposit(&amount);
+}
+
+// makes sure the vault holds at least `amount` idle by recalling the missing part from the strategy
+fn recall_from_strategy(e: &Env, amount: i128) {
+    let idle = get_idle_balance(e);
+    if !has_strategy(e) || idle >= amount {
+        return;
+    }
+
+    strategy::Client::new(e, get_strategy(e)).withdraw(&(amount - idle));
+}
+
+fn transfer(e: &Env, to: &Identifier, amount: i128) {
+    recall_from_strategy(e, amount);
+    transfer_token(e, get_token_id(e), to, amount);
+}
+
+fn transfer_token(e: &Env, token_id: BytesN<32>, to: &Identifier, amount: i128) {
+    let client = token::Client::new(e, token_id);
+    client.xfer(
+        &Signature::Invoker,
+        &client.nonce(&Signature::Invoker.identifier(e)),
+        to,
+        &amount,
+    );
+}
+
+fn transfer_in_vault(e: &Env, from: &Identifier, amount: &i128) {
+    transfer_token_in_vault(e, get_token_id(e), from, amount);
+}
+
+fn transfer_token_in_vault(e: &Env, token_id: BytesN<32>, from: &Identifier, amount: &i128) {
+    let client = token::Client::new(e, token_id);
+    let vault_id = get_contract_id(e);
+
+    client.xfer_from(&Signature::Invoker, &0, from, &vault_id, amount);
+}
+
+// pulls `amount` of the deposit token from `from` and returns how much of the vault's
+// token it's worth, measured by what the converter actually sent back
+fn receive_deposit(e: &Env, from: &Identifier, amount: i128) -> i128 {
+    if !has_converter(e) {
+        transfer_in_vault(e, from, &amount);
+        return amount;
+    }
+
+    let (token_id, converter) = get_converter(e);
+    transfer_token_in_vault(e, token_id.clone(), from, &amount);
+
+    let balance_before = get_token_balance(e);
+    transfer_token(
+        e,
+        token_id,
+        &Identifier::Contract(converter.clone()),
+        amount,
+    );
+    converter::Client::new(e, converter).convert(&amount);
+
+    get_token_balance(e) - balance_before
+}
+
+fn has_administrator(e: &Env) -> bool {
+    let key = DataKey::Admin;
+    e.storage().has(key)
+}
+
+fn read_administrator(e: &Env) -> Identifier {
+    let key = DataKey::Admin;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::NotInitialized))
+        .unwrap()
+}
+
+fn write_administrator(e: &Env, id: Identifier) {
+    let key = DataKey::Admin;
+    e.storage().set(key, id);
+}
+
+// the vault itself and all-zero keys or contract ids can't sign admin actions
+fn is_valid_admin(e: &Env, admin: &Identifier) -> bool {
+    let zero = BytesN::from_array(e, &[0; 32]);
+    match admin {
+        Identifier::Contract(id) => *id != e.get_current_contract() && *id != zero,
+        Identifier::Ed25519(key) => *key != zero,
+        Identifier::Account(_) => true,
+    }
+}
+
+fn read_nonce(e: &Env, id: &Identifier) -> i128 {
+    let key = DataKey::Nonce(id.clone());
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
+
+fn verify_and_consume_nonce(e: &Env, auth: &Signature, expected_nonce: i128) {
+    match auth {
+        Signature::Invoker => {
+            if expected_nonce != 0 {
+                panic_with_error!(e, VaultError::InvalidNonce)
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let id = auth.identifier(e);
+    if expected_nonce != read_nonce(e, &id) {
+        panic_with_error!(e, VaultError::InvalidNonce)
+    }
+
+    let key = DataKey::Nonce(id);
+    e.storage().set(key, expected_nonce + 1);
+}
+
+fn read_role(e: &Env, id: Identifier) -> Option<Role> {
+    let key = DataKey::Role(id);
+    e.storage().get(key).map(|role| role.unwrap())
+}
+
+fn write_role(e: &Env, id: Identifier, role: Role) {
+    let key = DataKey::Role(id);
+    e.storage().set(key, role);
+}
+
+fn check_role(e: &Env, auth: &Signature, role: Role) {
+    let auth_id = auth.identifier(e);
+    match read_role(e, auth_id) {
+        Some(Role::Admin) => {}
+        Some(Role::Operator) if role == Role::Operator => {}
+        _ => panic_with_error!(e, VaultError::NotAuthorized),
+    }
+}
+
+fn check_admin(e: &Env, auth: &Signature) {
+    check_role(e, auth, Role::Admin);
+}
+
+fn check_signer(e: &Env, auth: &Signature, id: &Identifier) {
+    if auth.identifier(e) != *id {
+        panic_with_error!(e, VaultError::NotAuthorized)
+    }
+}
+
+// with a cooldown set, withdrawing needs a request made at least `cooldown` seconds ago
+fn withdraw_request_error(e: &Env, id: Identifier) -> Option<VaultError> {
+    let cooldown = get_cooldown(e);
+    if cooldown == 0 {
+        return None;
+    }
+
+    let requested_at: u64 = match e.storage().get(DataKey::WithdReq(id)) {
+        Some(requested_at) => requested_at.unwrap(),
+        None => return Some(VaultError::NoWithdrawRequest),
+    };
+
+    if e.ledger().timestamp() < requested_at + cooldown {
+        Some(VaultError::CooldownActive)
+    } else {
+        None
+    }
+}
+
+fn check_withdraw_request(e: &Env, id: Identifier) {
+    if let Some(error) = withdraw_request_error(e, id.clone()) {
+        panic_with_error!(e, error)
+    }
+
+    e.storage().remove(DataKey::WithdReq(id));
+}
+
+fn check_allowed(e: &Env, id: Identifier) {
+    if get_allowlist(e) && !is_allowed(e, id) {
+        panic_with_error!(e, VaultError::NotAllowlisted)
+    }
+}
+
+// with a limit set, a single withdraw can't pay out more than `bps` of the total assets
+fn exceeds_max_withdraw(e: &Env, payout: i128) -> bool {
+    let bps = get_max_withdraw(e);
+    bps != 0 && payout > mul_div_floor(e, get_total_assets(e), bps as i128, 10000)
+}
+
+fn check_max_withdraw(e: &Env, payout: i128) {
+    if exceeds_max_withdraw(e, payout) {
+        panic_with_error!(e, VaultError::WithdrawLimit)
+    }
+}
+
+fn check_positive(e: &Env, amount: i128) {
+    if amount <= 0 {
+        panic_with_error!(e, VaultError::InvalidAmount)
+    }
+}
+
+fn check_min_deposit(e: &Env, amount: i128) {
+    if amount < get_min_deposit(e) {
+        panic_with_error!(e, VaultError::BelowMinDeposit)
+    }
+
+    if amount % get_deposit_unit(e) != 0 {
+        panic_with_error!(e, VaultError::InvalidDepositUnit)
+    }
+}
+
+// shares to mint for `amount` deposited on top of `prev_assets`
+fn deposit_shares(e: &Env, amount: i128, prev_assets: i128) -> i128 {
+    let tot_supply = get_tot_supply(e);
+
+    if 0 == tot_supply {
+        // assets left over after everyone exited (dust, donations) aren't handed to the
+        // next depositor: they're minted as deposit-less shares to the admin at 1:1. They're
+        // tracked from then on, reconcile would otherwise take a fee on them a second time
+        if prev_assets > 0 {
+            mint_shares(e, read_administrator(e), prev_assets, 0);
+            put_tracked(e, get_total_assets(e));
+        }
+
+        amount
+    } else {
+        let offset = get_offset(e);
+        let shares = mul_div_floor(e, amount, tot_supply + offset, prev_assets + offset);
+
+        // the product is known not to overflow at this point
+        if get_whole_shares(e) && (amount * (tot_supply + offset)) % (prev_assets + offset) != 0 {
+            panic_with_error!(e, VaultError::FractionalShares)
+        }
+
+        if shares == 0 {
+            panic_with_error!(e, VaultError::ZeroShares)
+        }
+
+        shares
+    }
+}
+
+fn read_ref_volume(e: &Env, referral: BytesN<32>) -> i128 {
+    let key = DataKey::RefVolume(referral);
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
+
+// minting rounds in the holders' favor, so a lower price than before a deposit means assets
+// went missing somewhere along the deposit (e.g. in the strategy)
+fn check_price_drop(e: &Env, price_before: i128) {
+    if share_price(e) < price_before - 1 {
+        panic_with_error!(e, VaultError::PriceDrop)
+    }
+}
+
+// every deposit is published under its recipient with the credited amount and the referral tag,
+// if there is one
+fn publish_deposit(e: &Env, recipient: Identifier, amount: i128, referral: Option<BytesN<32>>) {
+    e.events()
+        .publish((symbol!("deposit"), recipient), (amount, referral));
+}
+
+// pulls `amount` from `payer` and mints the shares it buys to `recipient`, returns the shares.
+// A referral tag gets the deposited assets added to its volume
+fn deposit_from(
+    e: &Env,
+    payer: &Identifier,
+    recipient: Identifier,
+    amount: i128,
+    referral: Option<BytesN<32>>,
+) -> i128 {
+    check_positive(e, amount);
+    check_deposits_open(e);
+    check_allowed(e, recipient.clone());
+    let price_before = share_price(e);
+    let amount = receive_deposit(e, payer, amount);
+    check_min_deposit(e, amount);
+    add_tracked(e, amount);
+
+    if let Some(referral) = referral.clone() {
+        let volume = read_ref_volume(e, referral.clone()) + amount;
+        e.storage().set(DataKey::RefVolume(referral), volume);
+    }
+    publish_deposit(e, recipient.clone(), amount, referral);
+
+    let shares = deposit_shares(e, amount, get_total_assets(e) - amount);
+    deploy_to_strategy(e, amount);
+
+    mint_shares(e, recipient, shares, amount);
+
+    check_price_drop(e, price_before);
+    checkpoint_price(e);
+
+    shares
+}
+
+// mints the performance fee on `gain`, which has already reached the total assets, as
+// deposit-less shares to the admin. Nothing is taken when there's no gain
+fn mint_perf_fee(e: &Env, gain: i128) {
+    if gain <= 0 {
+        return;
+    }
+
+    let tot_supply = get_tot_supply(e);
+    let fee = mul_div_floor(e, gain, get_perf_fee(e) as i128, 10000);
+    if fee > 0 && tot_supply > 0 {
+        // the fee shares dilute holders by exactly the fee amount
+        let offset = get_offset(e);
+        let fee_shares = mul_div_floor(
+            e,
+            fee,
+            tot_supply + offset,
+            get_total_assets(e) - fee + offset,
+        );
+        mint_shares(e, read_administrator(e), fee_shares, 0);
+    }
+}
+
+// assets that can be paid out right away: the idle tokens and what the strategy can return
+fn get_liquidity(e: &Env) -> i128 {
+    let idle = get_idle_balance(e);
+    if !has_strategy(e) {
+        return idle;
+    }
+
+    idle + strategy::Client::new(e, get_strategy(e)).available()
+}
+
+// burns all of `to`'s batches and returns what they're owed, the caller pays it out.
+// There has to be something to burn, and when `pay_now` the vault has to be able to pay
+// right away, which is checked before any share is burned
+fn exit_position(e: &Env, to: Identifier, pay_now: bool) -> i128 {
+    check_positive(e, get_user_shares(e, to.clone()));
+    check_withdraw_request(e, to.clone());
+
+    // the deposits of every batch are returned along with the fees
+    let (amount, deposits) = exit_amounts(e, &to);
+    let payout = amount + deposits;
+    check_max_withdraw(e, payout);
+
+    if pay_now && get_liquidity(e) < payout {
+        panic_with_error!(e, VaultError::InsufficientLiquidity)
+    }
+
+    for batch_el in get_user_batches(e, to.clone()).iter() {
+        let batch_ts = batch_el.unwrap_or_else(|_| panic_with_error!(e, VaultError::BatchNotFound));
+        let batch: BatchObj = e
+            .storage()
+            .get(DataKey::Batch(BatchKey(to.clone(), batch_ts)))
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::BatchNotFound))
+            .unwrap();
+
+        burn_shares(e, to.clone(), batch.curr_s, batch_ts);
+    }
+
+    add_tracked(e, -payout);
+
+    payout
+}
+
+fn get_withdraw_queue(e: &Env) -> Vec<Identifier> {
+    let key = DataKey::WithdQueue;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| Ok(Vec::new(e)))
+        .unwrap()
+}
+
+fn read_claim(e: &Env, id: Identifier) -> WithdClaim {
+    let key = DataKey::Claim(id);
+    e.storage()
+        .get(key)
+        .unwrap_or(Ok(WithdClaim(0, 0)))
+        .unwrap()
+}
+
+fn write_claim(e: &Env, id: Identifier, claim: WithdClaim) {
+    let key = DataKey::Claim(id);
+    if claim.0 == 0 && claim.1 == 0 {
+        e.storage().remove(key);
+    } else {
+        e.storage().set(key, claim);
+    }
+}
+
+// withdraw_amounts valued against the actual total assets, so losses are shared by everyone
+// (fees come out negative). The last holder out takes whatever is left, rounding dust included
+fn exit_amounts(e: &Env, to: &Identifier) -> (i128, i128) {
+    let (amount, deposits) = withdraw_amounts(e, to);
+
+    if get_user_shares(e, to.clone()) == get_tot_supply(e) {
+        (get_total_assets(e) - deposits, deposits)
+    } else {
+        (amount, deposits)
+    }
+}
+
+// fees and deposits owed to `to` when withdrawing all of their batches
+fn withdraw_amounts(e: &Env, to: &Identifier) -> (i128, i128) {
+    let batches = get_user_batches(e, to.clone());
+    log!(e, "batches {}", batches.clone());
+
+    let mut amount: i128 = 0;
+    let mut deposits: i128 = 0;
+    let mut temp_supply: i128 = get_tot_supply(e);
+    let mut temp_balance: i128 = get_total_assets(e);
+    let offset = get_offset(e);
+
+    for batch_el in batches.iter() {
+        let batch_ts = batch_el.unwrap_or_else(|_| panic_with_error!(e, VaultError::BatchNotFound));
+
+        let key = DataKey::Batch(BatchKey(to.clone(), batch_ts));
+        let batch: BatchObj = e
+            .storage()
+            .get(key)
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::BatchNotFound))
+            .unwrap();
+
+        let deposit = batch.deposit;
+        let init_s = batch.init_s;
+        let curr_s = batch.curr_s;
+
+        let new_deposit = mul_div_floor(
+            e,
+            deposit,
+            mul_div_floor(e, curr_s, 10000000, init_s),
+            10000000,
+        );
+        let fee_amount =
+            mul_div_floor(e, temp_balance + offset, curr_s, temp_supply + offset) - new_deposit;
+
+        amount += fee_amount;
+        deposits += new_deposit;
+
+        temp_balance -= fee_amount;
+        temp_supply -= curr_s;
+
+        // the deposit is treated as reinvested, the shares it would mint are rounded up so
+        // that the remaining batches aren't valued against a smaller supply
+        if new_deposit == 0 {
+            log!(e, "no deposit to reinvest", amount);
+        } else if temp_balance + offset != new_deposit {
+            temp_supply += mul_div_ceil(
+                e,
+                new_deposit,
+                temp_supply + offset,
+                temp_balance - new_deposit + offset,
+            );
+            log!(e, "deposit != balance", amount);
+        } else {
+            temp_supply += mul_div_floor(e, new_deposit, temp_supply, new_deposit);
+            log!(e, "deposit == balance", amount);
+        }
+    }
+
+    (amount, deposits)
+}
+
+fn get_snapshot_id(e: &Env) -> u32 {
+    let key = DataKey::SnapId;
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
+
+// snapshot ids `id`'s balance was recorded for, in increasing order
+fn get_holder_snaps(e: &Env, id: Identifier) -> Vec<u32> {
+    let key = DataKey::SnapIds(id);
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| Ok(Vec::new(e)))
+        .unwrap()
+}
+
+// snapshots are taken lazily: the first time a balance changes after a snapshot, the balance
+// it had until then is recorded under the latest snapshot id
+fn capture_snapshot(e: &Env, id: Identifier) {
+    let snap_id = get_snapshot_id(e);
+    if snap_id == 0 {
+        return;
+    }
+
+    let mut snaps = get_holder_snaps(e, id.clone());
+    if let Some(Ok(last)) = snaps.last() {
+        if last == snap_id {
+            return;
+        }
+    }
+
+    let key = DataKey::Snapshot(SnapKey(snap_id, id.clone()));
+    e.storage().set(key, get_user_shares(e, id.clone()));
+
+    snaps.push_back(snap_id);
+    e.storage().set(DataKey::SnapIds(id), snaps);
+}
+
+fn get_reward_tokens(e: &Env) -> Vec<BytesN<32>> {
+    let key = DataKey::RwdTokens;
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| Ok(Vec::new(e)))
+        .unwrap()
+}
+
+// rewards distributed per share since the start, scaled by REWARD_SCALE
+fn get_reward_index(e: &Env, token: BytesN<32>) -> i128 {
+    let key = DataKey::RwdIndex(token);
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
+
+// rewards `id` is owed for `token`, including what accrued since it was last settled
+fn pending_reward(e: &Env, token: BytesN<32>, id: Identifier) -> i128 {
+    let key = RwdKey(token.clone(), id.clone());
+    let debt: i128 = e
+        .storage()
+        .get(DataKey::RwdDebt(key.clone()))
+        .unwrap_or(Ok(0))
+        .unwrap();
+    let owed: i128 = e
+        .storage()
+        .get(DataKey::RwdOwed(key))
+        .unwrap_or(Ok(0))
+        .unwrap();
+
+    let index = get_reward_index(e, token);
+    owed + mul_div_floor(e, get_user_shares(e, id), index - debt, REWARD_SCALE)
+}
+
+fn write_reward(e: &Env, token: BytesN<32>, id: Identifier, owed: i128) {
+    let key = RwdKey(token.clone(), id);
+    e.storage()
+        .set(DataKey::RwdDebt(key.clone()), get_reward_index(e, token));
+    e.storage().set(DataKey::RwdOwed(key), owed);
+}
+
+// books the rewards accrued on `id`'s current shares, must run before its balance changes
+fn settle_rewards(e: &Env, id: Identifier) {
+    for token in get_reward_tokens(e).iter() {
+        let token = token.unwrap();
+        let owed = pending_reward(e, token.clone(), id.clone());
+        write_reward(e, token, id.clone(), owed);
+    }
+}
+
+fn claim_reward(e: &Env, token: BytesN<32>, holder: Identifier) -> i128 {
+    let owed = pending_reward(e, token.clone(), holder.clone());
+    write_reward(e, token.clone(), holder.clone(), 0);
+
+    if owed > 0 {
+        transfer_token(e, token, &holder, owed);
+    }
+
+    owed
+}
+
+fn mint_shares(e: &Env, to: Identifier, shares: i128, deposit: i128) -> u64 {
+    capture_snapshot(e, to.clone());
+    settle_rewards(e, to.clone());
+
+    let tot_supply = get_tot_supply(e);
+    put_tot_supply(e, tot_supply + shares);
+    put_tot_deposit(e, get_tot_deposit(e) + deposit);
+
+    let ts = e.ledger().timestamp();
+    let key = DataKey::Batch(BatchKey(to.clone(), ts));
+
+    // shares minted to the same id in the same ledger end up in the same batch. A batch that
+    // was partly burned is first rebased to what's left of it, so the deposit stays prorated
+    let val = if let Some(batch) = e.storage().get::<DataKey, BatchObj>(key.clone()) {
+        let batch = batch.unwrap();
+        let left = mul_div_floor(e, batch.deposit, batch.curr_s, batch.init_s);
+        BatchObj {
+            init_s: batch.curr_s + shares,
+            deposit: left + deposit,
+            curr_s: batch.curr_s + shares,
+        }
+    } else {
+        add_user_batch(e, to.clone(), ts);
+        BatchObj {
+            init_s: shares,
+            deposit,
+            curr_s: shares,
+        }
+    };
+
+    e.storage().set(key, val);
+    put_last_price(e, to.clone());
+    publish_balance(e, to);
+
+    ts
+}
+
+fn get_user_batches(e: &Env, id: Identifier) -> Vec<u64> {
+    let key = DataKey::Batches(id);
+    e.storage()
+        .get(key)
+        .unwrap_or_else(|| Ok(Vec::new(e)))
+        .unwrap()
+}
+
+fn add_user_batch(e: &Env, id: Identifier, batch_ts: u64) {
+    let mut batches = get_user_batches(e, id.clone());
+    batches.push_front(batch_ts);
+
+    let key = DataKey::Batches(id);
+    e.storage().set(key, batches);
+}
+
+fn remove_user_batch(e: &Env, id: Identifier, batch_ts: u64) {
+    let mut batches = get_user_batches(e, id.clone());
+    let batch_idx = batches.iter().position(|x| x.unwrap() == batch_ts).unwrap();
+
+    batches.remove(batch_idx as u32);
+
+    let key = DataKey::Batches(id);
+    e.storage().set(key, batches);
+}
+
+// returns the part of the batch's deposit the burned shares were backing
+fn burn_shares(e: &Env, to: Identifier, shares: i128, batch_ts: u64) -> i128 {
+    capture_snapshot(e, to.clone());
+    settle_rewards(e, to.clone());
+
+    let tot_supply = get_tot_supply(e);
+    let key = DataKey::Batch(BatchKey(to.clone(), batch_ts));
+
+    let mut batch: BatchObj = e
+        .storage()
+        .get(key.clone())
+        .unwrap_or_else(|| panic_with_error!(e, VaultError::BatchNotFound))
+        .unwrap();
+    if batch.curr_s < shares {
+        panic_with_error!(e, VaultError::InsufficientShares)
+    }
+
+    batch.curr_s -= shares;
+    put_tot_supply(e, tot_supply - shares);
+
+    // the principal leaves the vault's accounting along with the shares backing it
+    let burned_deposit = mul_div_floor(
+        e,
+        batch.deposit,
+        mul_div_floor(e, shares, 10000000, batch.init_s),
+        10000000,
+    );
+    put_tot_deposit(e, get_tot_deposit(e) - burned_deposit);
+
+    if batch.curr_s == 0 {
+        e.storage().remove(key); // if there are 0 shares remove the batch
+        remove_user_batch(e, to.clone(), batch_ts);
+    } else {
+        e.storage().set(key, batch);
+    }
+
+    put_last_price(e, to.clone());
+    publish_balance(e, to);
+    burned_deposit
+}
+
+// moves `shares` from `from`'s batches, newest first, to a new batch of `to` carrying
+// the deposits they were backing
+fn move_shares(e: &Env, from: Identifier, to: Identifier, shares: i128) {
+    if get_user_shares(e, from.clone()) < shares {
+        panic_with_error!(e, VaultError::InsufficientShares)
+    }
+
+    let mut remaining = shares;
+    let mut deposit: i128 = 0;
+    for batch_el in get_user_batches(e, from.clone()).iter() {
+        if remaining == 0 {
+            break;
+        }
+
+        let batch_ts = batch_el.unwrap();
+        let batch: BatchObj = e
+            .storage()
+            .get(DataKey::Batch(BatchKey(from.clone(), batch_ts)))
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::BatchNotFound))
+            .unwrap();
+
+        let moved = if batch.curr_s < remaining {
+            batch.curr_s
+        } else {
+            remaining
+        };
+        deposit += burn_shares(e, from.clone(), moved, batch_ts);
+        remaining -= moved;
+    }
+
+    mint_shares(e, to, shares, deposit);
+
+    // burn_shares recorded the sender's price while the moved shares were out of the supply
+    put_last_price(e, from);
+}
+
+fn read_allowance(e: &Env, from: Identifier, spender: Identifier) -> i128 {
+    let key = DataKey::Allowance(AllowKey(from, spender));
+    e.storage().get(key).unwrap_or(Ok(0)).unwrap()
+}
+
+fn write_allowance(e: &Env, from: Identifier, spender: Identifier, amount: i128) {
+    let key = DataKey::Allowance(AllowKey(from, spender));
+    if amount == 0 {
+        e.storage().remove(key);
+    } else {
+        e.storage().set(key, amount);
+    }
+}
+
+fn spend_allowance(e: &Env, from: Identifier, spender: Identifier, amount: i128) {
+    let allowance = read_allowance(e, from.clone(), spender.clone());
+    if allowance < amount {
+        panic_with_error!(e, VaultError::InsufficientAllowance)
+    }
+
+    write_allowance(e, from, spender, allowance - amount);
+}
+
+// total shares held by `id` across all of its batches
+fn get_user_shares(e: &Env, id: Identifier) -> i128 {
+    let mut shares: i128 = 0;
+
+    for batch_el in get_user_batches(e, id.clone()).iter() {
+        let batch_ts = batch_el.unwrap();
+        let batch: BatchObj = e
+            .storage()
+            .get(DataKey::Batch(BatchKey(id.clone(), batch_ts)))
+            .unwrap_or_else(|| panic_with_error!(e, VaultError::BatchNotFound))
+            .unwrap();
+
+        shares += batch.curr_s;
+    }
+
+    shares
+}
+
+// price per share at the last time `id`'s balance changed
+fn put_last_price(e: &Env, id: Identifier) {
+    let key = DataKey::LastPrice(id);
+    e.storage().set(key, share_price(e));
+}
+
+fn publish_balance(e: &Env, id: Identifier) {
+    let balance = get_user_shares(e, id.clone());
+    e.events().publish((symbol!("balance"), id), balance);
+}
+
+pub trait VaultContractTrait {
+    // Sets the admin and the vault's token id
+    fn initialize(e: Env, admin: Identifier, token_id: BytesN<32>);
+
+    // Returns the nonce for the admin
+    fn nonce(e: Env) -> i128;
+
+    // deposit shares into the vault: mints the vault shares to "from", who must be the signer.
+    // `amount` is in the deposit token, shares are minted for its value in the vault's token.
+    // When whole shares are required the amount must be a multiple of the share price,
+    // so depositors may have to round their amount to get accepted. Returns the shares minted
+    fn deposit(e: Env, auth: Auth, from: Identifier, amount: i128) -> i128;
+
+    /// withdraw fees, "to" must be the signer
+    fn fee_withd(e: Env, auth: Auth, to: Identifier, batch_ts: u64, shares: i128);
+
+    // get vault shares for a user
+    fn get_shares(e: Env, id: Identifier, batch_ts: u64) -> BatchObj;
+
+    fn batches(e: Env, id: Identifier) -> Vec<u64>;
+
+    // withdraw all of "to"'s shares, "to" must be the signer. Returns the assets paid out.
+    // Fails without burning anything when the vault can't pay right away, see queue_wd
+    fn withdraw(e: Env, auth: Auth, to: Identifier) -> i128;
+
+    // total assets backing the vault's shares
+    fn tot_assets(e: Env) -> i128;
+
+    // fees that arrived in the vault and haven't been withdrawn by the depositors yet
+    fn pend_fees(e: Env) -> i128;
+
+    // admin only: recovers tokens other than the vault's asset that were sent to the vault by mistake
+    fn sweep(e: Env, auth: Auth, token: BytesN<32>, to: Identifier);
+
+    // admin only: rejects deposits that would mint a fractional amount of shares
+    fn set_whole(e: Env, auth: Auth, enabled: bool);
+
+    // operator or admin: stops (or resumes) new deposits, withdrawals keep working
+    fn pause_dep(e: Env, auth: Auth, paused: bool);
+
+    // admin only: sets the strategy new deposits are deployed to, recalling everything from the previous one
+    fn set_strat(e: Env, auth: Auth, strategy: BytesN<32>);
+
+    // admin only: sets the performance fee (in bps) taken on harvested yield and reconciled gains
+    fn set_perf(e: Env, auth: Auth, bps: u32);
+
+    // operator or admin: pulls the strategy's yield into the vault, minting the performance fee
+    // as shares to the admin. Returns the realized yield
+    fn harvest(e: Env, auth: Auth) -> i128;
+
+    // estimated assets redeemable after depositing `amount` now and holding for `days`
+    // at `apy_bps` (simple interest over the current share price)
+    fn proj_pos(e: Env, amount: i128, apy_bps: u32, days: u32) -> i128;
+
+    // admin only: when enabled only allowed ids can deposit
+    fn set_allow(e: Env, auth: Auth, enabled: bool);
+
+    // admin only: adds `id` to the deposit allowlist
+    fn allow(e: Env, auth: Auth, id: Identifier);
+
+    // admin only: removes `id` from the deposit allowlist
+    fn disallow(e: Env, auth: Auth, id: Identifier);
+
+    // admin only: deposits each entry's amount of the deposit token on behalf of its id. The
+    // total is pulled from the admin and converted once, each id is credited its pro rata part
+    // of what was received. At most 50 entries per call
+    fn dep_batch(e: Env, auth: Auth, entries: Vec<DepEntry>);
+
+    // current fee, share, allowlist, withdrawal and deposit settings
+    fn config(e: Env) -> VaultConfig;
+
+    // admin only: validates and applies all settings at once
+    fn upd_config(e: Env, auth: Auth, config: VaultConfig);
+
+    // previews withdraw for `id`: the deposits and fees paid out, how much of the payout
+    // has to be recalled from the strategy and what, if anything, would stop it
+    fn sim_withd(e: Env, id: Identifier) -> WithdSim;
+
+    // admin only: deposits below `amount` are rejected
+    fn set_mindep(e: Env, auth: Auth, amount: i128);
+
+    // admin only: seconds that must pass between req_withd and withdraw, 0 disables it
+    fn set_cooldn(e: Env, auth: Auth, seconds: u64);
+
+    // starts the withdraw cooldown for "id", who must be the signer
+    fn req_withd(e: Env, auth: Auth, id: Identifier);
+
+    // total shares held by "id" across all of its batches
+    fn shares(e: Env, id: Identifier) -> i128;
+
+    // last `n` (timestamp, price per share scaled by 10^7) checkpoints, oldest first
+    fn price_hist(e: Env, n: u32) -> Vec<PricePt>;
+
+    // admin only: gives `role` to "id", the admin set at initialization keeps its own
+    fn grant_role(e: Env, auth: Auth, id: Identifier, role: Role);
+
+    // admin only: removes any role from "id", the admin set at initialization keeps its role
+    fn drop_role(e: Env, auth: Auth, id: Identifier);
+
+    // role of "id", if any
+    fn role(e: Env, id: Identifier) -> Option<Role>;
+
+    // admin earnings split between crystallized fees and the fee pending on unharvested yield
+    fn admin_earn(e: Env) -> Earnings;
+
+    // admin only: deposits are taken in `token` and turned into the vault's token by `converter`.
+    // Setting the vault's own token goes back to plain deposits
+    fn set_deptok(e: Env, auth: Auth, token: BytesN<32>, converter: BytesN<32>);
+
+    // token deposit takes its amount in
+    fn dep_token(e: Env) -> BytesN<32>;
+
+    // value of "id"'s shares in the vault's token at the current share price
+    fn bal_assets(e: Env, id: Identifier) -> i128;
+
+    // total assets against the backing the recorded deposits require
+    fn solvency(e: Env) -> Solvency;
+
+    // like deposit, but the tokens are pulled from "payer", who must be the signer, and
+    // the shares are minted to "recipient"
+    fn dep_for(e: Env, auth: Auth, payer: Identifier, recipient: Identifier, amount: i128) -> i128;
+
+    // price per share (scaled by 10^7) paid for the batch at `lot` in batches(id), newest first
+    fn lot_price(e: Env, id: Identifier, lot: u32) -> i128;
+
+    // lets "spender" move up to `amount` of "owner"'s shares, "owner" must be the signer.
+    // A zero amount revokes the allowance
+    fn approve(e: Env, auth: Auth, owner: Identifier, spender: Identifier, amount: i128);
+
+    // shares "spender" can still move out of "owner"'s position
+    fn allowance(e: Env, owner: Identifier, spender: Identifier) -> i128;
+
+    // moves `amount` of "from"'s shares to "to" out of the allowance of "spender", who must be
+    // the signer. The deposits backing the shares move along with them
+    fn xfer_from(
+        e: Env,
+        auth: Auth,
+        spender: Identifier,
+        from: Identifier,
+        to: Identifier,
+        amount: i128,
+    );
+
+    // admin only: adds `id` to the deposit allowlist until the `expires` timestamp
+    fn allow_exp(e: Env, auth: Auth, id: Identifier, expires: u64);
+
+    // anyone: removes the allowlist entries that have expired, returns how many were removed
+    fn prune_exp(e: Env) -> u32;
+
+    // annualized change in the price per share (in bps) between the oldest checkpoint of the
+    // last `window` seconds and the latest one. Fees are already out of the price, so this is
+    // net of them. 0 while there aren't two checkpoints in the window
+    fn net_apy(e: Env, window: u64) -> i32;
+
+    // admin only: takes a snapshot of every holder's shares and returns its id
+    fn snapshot(e: Env, auth: Auth) -> u32;
+
+    // shares "holder" had when snapshot `snap_id` was taken
+    fn shares_at(e: Env, snap_id: u32, holder: Identifier) -> i128;
+
+    // admin only: deposits must be a multiple of `unit`, 1 accepts any amount
+    fn set_unit(e: Env, auth: Auth, unit: i128);
+
+    // admin only: pulls `amount` of `token` from the admin and distributes it pro-rata to the
+    // current share holders
+    fn notify_rwd(e: Env, auth: Auth, token: BytesN<32>, amount: i128);
+
+    // pays "holder", who must be the signer, the `token` rewards accrued on its shares
+    fn claim_rwd(e: Env, auth: Auth, holder: Identifier, token: BytesN<32>) -> i128;
+
+    // `token` rewards "holder" can claim
+    fn rewards(e: Env, holder: Identifier, token: BytesN<32>) -> i128;
+
+    // claim_rwd for each of `tokens` at once, returns the amounts paid in the same order
+    fn claim_all(e: Env, auth: Auth, holder: Identifier, tokens: Vec<BytesN<32>>) -> Vec<i128>;
+
+    // admin only: caps a single withdraw at `bps` of the total assets, 0 disables the cap
+    fn set_maxwd(e: Env, auth: Auth, bps: u32);
+
+    // admin only: keeps `bps` of the total assets idle in the vault when deploying deposits to
+    // the strategy, so that withdrawals can be paid without a recall
+    fn set_buffer(e: Env, auth: Auth, bps: u32);
+
+    // yield earned by "id"'s shares since its balance last changed
+    fn accr_since(e: Env, id: Identifier) -> i128;
+
+    // admin only: proposes "candidate" as the next admin, who has to accept to take over
+    fn prop_admin(e: Env, auth: Auth, candidate: Identifier);
+
+    // the proposed admin, who must be the signer, takes over from the current one
+    fn accept_adm(e: Env, auth: Auth);
+
+    // admin only: returns the gain (or loss) of the total assets over the tracked ones, from
+    // donations or a strategy returning more or less than expected, and tracks it from then on.
+    // The performance fee is minted on gains only
+    fn reconcile(e: Env, auth: Auth) -> i128;
+
+    // whether initialize has run
+    fn is_init(e: Env) -> bool;
+
+    // like withdraw, but instead of being paid right away "to" gets a claim on the payout,
+    // for when the strategy can't return the assets yet. Returns the amount queued
+    fn queue_wd(e: Env, auth: Auth, to: Identifier) -> i128;
+
+    // admin only: sets aside idle assets for the queued claims, oldest first, until one of them
+    // can't be covered. What's missing is recalled from the strategy first, as far as it's
+    // available. Returns how many claims became claimable
+    fn process_q(e: Env, auth: Auth) -> u32;
+
+    // pays "to", who must be the signer, its claimable queued withdrawals
+    fn claim_wd(e: Env, auth: Auth, to: Identifier) -> i128;
+
+    // (queued, claimable) withdraw amounts of "id"
+    fn wd_claim(e: Env, id: Identifier) -> WithdClaim;
+
+    // total assets, supply, price per share and fee read together in one call
+    fn quote(e: Env) -> Quote;
+
+    // like deposit, also crediting the deposited assets to the `referral` tag's volume
+    fn dep_ref(e: Env, auth: Auth, from: Identifier, amount: i128, referral: BytesN<32>) -> i128;
+
+    // assets deposited under the `referral` tag
+    fn ref_volume(e: Env, referral: BytesN<32>) -> i128;
+
+    // shares held by each of `ids`, in the same order. At most 50 ids per call
+    fn shares_of(e: Env, ids: Vec<Identifier>) -> Vec<i128>;
+}
+
+pub struct VaultContract;
+
+#[contractimpl]
+impl VaultContractTrait for VaultContract {
+    fn initialize(e: Env, admin: Identifier, token_id: BytesN<32>) {
+        log!(&e, "initializing");
+
+        if has_administrator(&e) {
+            panic_with_error!(&e, VaultError::AlreadyInitialized);
+        }
+
+        // the vault holding shares of itself would make every balance lookup recursive
+        if token_id == e.get_current_contract() {
+            panic_with_error!(&e, VaultError::InvalidToken)
+        }
+
+        if !is_valid_admin(&e, &admin) {
+            panic_with_error!(&e, VaultError::InvalidAdmin)
+        }
+
+        write_administrator(&e, admin.clone());
+        write_role(&e, admin, Role::Admin);
+        put_offset(&e, VIRTUAL_OFFSET);
+
+        put_token_id(&e, token_id)
+    }
+
+    fn nonce(e: Env) -> i128 {
+        read_nonce(&e, &read_administrator(&e))
+    }
+
+    fn deposit(e: Env, auth: Auth, from: Identifier, amount: i128) -> i128 {
+        log!(&e, "depositing");
+
+        check_signer(&e, &auth.sig, &from);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("deposit"),
+            (from.clone(), auth.nonce, amount),
+        );
+
+        deposit_from(&e, &from, from.clone(), amount, None)
+    }
+
+    fn get_shares(e: Env, id: Identifier, batch_ts: u64) -> BatchObj {
+        let key = DataKey::Batch(BatchKey(id, batch_ts));
+
+        let batch: BatchObj = e
+            .storage()
+            .get(key)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::BatchNotFound))
+            .unwrap();
+
+        batch
+    }
+
+    fn batches(e: Env, id: Identifier) -> Vec<u64> {
+        get_user_batches(&e, id)
+    }
+
+    fn fee_withd(e: Env, auth: Auth, to: Identifier, batch_ts: u64, shares: i128) {
+        check_signer(&e, &auth.sig, &to);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("fee_withd"),
+            (to.clone(), auth.nonce, batch_ts, shares),
+        );
+        check_positive(&e, shares);
+
+        let tot_supply = get_tot_supply(&e);
+        let tot_bal = get_total_assets(&e);
+        let batch: BatchObj = e
+            .storage()
+            .get(DataKey::Batch(BatchKey(to.clone(), batch_ts)))
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::BatchNotFound))
+            .unwrap();
+        let deposit = batch.deposit;
+        let init_s = batch.init_s;
+        let curr_s = batch.curr_s;
+
+        if curr_s < shares {
+            panic_with_error!(&e, VaultError::InsufficientShares);
+        }
+
+        let new_deposit = mul_div_floor(
+            &e,
+            deposit,
+            mul_div_floor(&e, shares, 10000000, init_s),
+            10000000,
+        );
+
+        let offset = get_offset(&e);
+        let fee_amount =
+            mul_div_floor(&e, tot_bal + offset, shares, tot_supply + offset) - new_deposit;
+        if fee_amount >= 0 {
+            // the request is only used up by a call that pays out
+            check_withdraw_request(&e, to.clone());
+            check_max_withdraw(&e, fee_amount);
+            transfer(&e, &to, fee_amount);
+            add_tracked(&e, -fee_amount);
+            burn_shares(&e, to.clone(), shares, batch_ts);
+            let new_tot_supply = get_tot_supply(&e);
+            let new_tot_bal = get_total_assets(&e);
+
+            //        if curr_s != shares {
+
+            if new_deposit == 0 {
+                // nothing left to reinvest (e.g. fee shares)
+            } else if tot_bal + offset != new_deposit {
+                let new_shares = mul_div_floor(
+                    &e,
+                    new_deposit,
+                    new_tot_supply + offset,
+                    new_tot_bal - new_deposit + offset,
+                );
+                mint_shares(&e, to, new_shares, new_deposit);
+            } else {
+                let new_shares = mul_div_floor(&e, new_deposit, tot_supply, new_deposit);
+                mint_shares(&e, to, new_shares, new_deposit);
+            }
+
+            checkpoint_price(&e);
+        }
+
+        //log!(&e, "new dep: {}, new shares:", new_deposit.clone(),);
+    }
+
+    fn withdraw(e: Env, auth: Auth, to: Identifier) -> i128 {
+        check_signer(&e, &auth.sig, &to);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(&e, &auth.sig, symbol!("withdraw"), (to.clone(), auth.nonce));
+
+        let payout = exit_position(&e, to.clone(), true);
+        transfer(&e, &to, payout);
+        checkpoint_price(&e);
+
+        payout
+    }
+
+    fn tot_assets(e: Env) -> i128 {
+        get_total_assets(&e)
+    }
+
+    fn pend_fees(e: Env) -> i128 {
+        let pending = get_total_assets(&e) - get_tot_deposit(&e);
+
+        if pending < 0 {
+            0
+        } else {
+            pending
+        }
+    }
+
+    fn sweep(e: Env, auth: Auth, token: BytesN<32>, to: Identifier) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("sweep"),
+            (auth_id, auth.nonce, token.clone(), to.clone()),
+        );
+
+        if token == get_token_id(&e) {
+            panic_with_error!(&e, VaultError::InvalidToken)
+        }
+
+        if get_reward_tokens(&e).contains(&token) {
+            panic_with_error!(&e, VaultError::InvalidToken)
+        }
+
+        let balance = token::Client::new(&e, token.clone()).balance(&get_contract_id(&e));
+        transfer_token(&e, token, &to, balance);
+    }
+
+    fn set_whole(e: Env, auth: Auth, enabled: bool) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_whole"),
+            (auth_id, auth.nonce, enabled),
+        );
+
+        put_whole_shares(&e, enabled);
+    }
+
+    fn pause_dep(e: Env, auth: Auth, paused: bool) {
+        check_role(&e, &auth.sig, Role::Operator);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("pause_dep"),
+            (auth_id, auth.nonce, paused),
+        );
+
+        put_deposits_paused(&e, paused);
+    }
+
+    fn set_strat(e: Env, auth: Auth, strategy: BytesN<32>) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_strat"),
+            (auth_id, auth.nonce, strategy.clone()),
+        );
+
+        if has_strategy(&e) {
+            let client = strategy::Client::new(&e, get_strategy(&e));
+            client.withdraw(&client.balance());
+        }
+
+        put_strategy(&e, strategy);
+    }
+
+    fn set_perf(e: Env, auth: Auth, bps: u32) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_perf"),
+            (auth_id, auth.nonce, bps),
+        );
+
+        check_bps(&e, bps);
+        put_perf_fee(&e, bps);
+    }
+
+    fn harvest(e: Env, auth: Auth) -> i128 {
+        check_role(&e, &auth.sig, Role::Operator);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(&e, &auth.sig, symbol!("harvest"), (auth_id, auth.nonce));
+
+        if !has_strategy(&e) {
+            panic_with_error!(&e, VaultError::NoStrategy)
+        }
+
+        let realized = strategy::Client::new(&e, get_strategy(&e)).harvest();
+        add_tracked(&e, realized);
+
+        mint_perf_fee(&e, realized);
+
+        checkpoint_price(&e);
+
+        e.events().publish((symbol!("harvest"),), realized);
+        realized
+    }
+
+    fn proj_pos(e: Env, amount: i128, apy_bps: u32, days: u32) -> i128 {
+        let redeemable = amount_for_shares(&e, shares_for_amount(&e, amount));
+        let growth = apy_bps as i128 * days as i128;
+
+        redeemable + mul_div_floor(&e, redeemable, growth, 10000 * 365)
+    }
+
+    fn set_allow(e: Env, auth: Auth, enabled: bool) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
 
-            let deposit = batch.deposit;
-            let init_s = batch.init_s;
-            let curr_s = batch.curr_s;
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_allow"),
+            (auth_id, auth.nonce, enabled),
+        );
 
-            let new_deposit = deposit * (curr_s * 10000000 / init_s) / 10000000;
-            let fee_amount = ((temp_balance * curr_s) / temp_supply) - new_deposit;
+        put_allowlist(&e, enabled);
+    }
+
+    fn allow(e: Env, auth: Auth, id: Identifier) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("allow"),
+            (auth_id, auth.nonce, id.clone()),
+        );
+
+        e.storage().set(DataKey::Allowed(id), u64::MAX);
+    }
+
+    fn disallow(e: Env, auth: Auth, id: Identifier) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("disallow"),
+            (auth_id, auth.nonce, id.clone()),
+        );
+
+        e.storage().remove(DataKey::Allowed(id));
+    }
+
+    fn dep_batch(e: Env, auth: Auth, entries: Vec<DepEntry>) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("dep_batch"),
+            (auth_id.clone(), auth.nonce, entries.clone()),
+        );
+
+        if entries.len() > MAX_BATCH {
+            panic_with_error!(&e, VaultError::BatchTooLarge)
+        }
 
-            amount += fee_amount;
+        check_deposits_open(&e);
 
-            temp_balance -= fee_amount;
-            temp_supply -= curr_s;
+        let mut total: i128 = 0;
+        for entry in entries.iter() {
+            let DepEntry(id, amount) = entry.unwrap();
+            check_positive(&e, amount);
 
-            //            transfer(&e, to.clone(), fee_amount);
-            burn_shares(&e, to.clone(), curr_s, batch_ts);
+            check_allowed(&e, id);
+            total += amount;
+        }
 
-            if temp_balance != new_deposit {
-                temp_supply += (new_deposit * temp_supply) / (temp_balance - new_deposit);
-                log!(&e, "deposit != balance", amount);
+        let price_before = share_price(&e);
+        let received = receive_deposit(&e, &auth_id, total);
+        add_tracked(&e, received);
+
+        // each entry is credited its part of what was received, the last one takes the rounding
+        // dust, and is minted as if deposited right after the previous one
+        let mut prev_assets = get_total_assets(&e) - received;
+        let mut credited: i128 = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            let DepEntry(id, amount) = entry.unwrap();
+            let amount = if i as u32 + 1 == entries.len() {
+                received - credited
             } else {
-                temp_supply += (new_deposit * temp_supply) / (new_deposit);
-                log!(&e, "deposit == balance", amount);
+                mul_div_floor(&e, received, amount, total)
+            };
+            check_min_deposit(&e, amount);
+            publish_deposit(&e, id.clone(), amount, None);
+
+            let shares = deposit_shares(&e, amount, prev_assets);
+            mint_shares(&e, id, shares, amount);
+            prev_assets += amount;
+            credited += amount;
+        }
+
+        deploy_to_strategy(&e, received);
+        check_price_drop(&e, price_before);
+        checkpoint_price(&e);
+    }
+
+    fn config(e: Env) -> VaultConfig {
+        VaultConfig {
+            perf_fee: get_perf_fee(&e),
+            whole_shrs: get_whole_shares(&e),
+            allowlist: get_allowlist(&e),
+            max_withd: get_max_withdraw(&e),
+            min_dep: get_min_deposit(&e),
+            cooldown: get_cooldown(&e),
+            dep_unit: get_deposit_unit(&e),
+            buffer_bps: get_buffer_bps(&e),
+            dep_paused: get_deposits_paused(&e),
+        }
+    }
+
+    fn upd_config(e: Env, auth: Auth, config: VaultConfig) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("upd_config"),
+            (auth_id, auth.nonce, config.clone()),
+        );
+
+        check_bps(&e, config.perf_fee);
+        check_bps(&e, config.max_withd);
+        check_bps(&e, config.buffer_bps);
+        check_deposit_unit(&e, config.dep_unit);
+        if config.min_dep < 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount)
+        }
+
+        put_perf_fee(&e, config.perf_fee);
+        put_whole_shares(&e, config.whole_shrs);
+        put_allowlist(&e, config.allowlist);
+        put_max_withdraw(&e, config.max_withd);
+        put_min_deposit(&e, config.min_dep);
+        put_cooldown(&e, config.cooldown);
+        put_deposit_unit(&e, config.dep_unit);
+        put_buffer_bps(&e, config.buffer_bps);
+        put_deposits_paused(&e, config.dep_paused);
+    }
+
+    fn sim_withd(e: Env, id: Identifier) -> WithdSim {
+        // an id without shares has nothing to withdraw, the first check withdraw makes. Past it
+        // exit_amounts would take the id for the last holder once the supply is zero and report
+        // the residual assets as its payout
+        if get_user_shares(&e, id.clone()) <= 0 {
+            return WithdSim {
+                deposits: 0,
+                fees: 0,
+                payout: 0,
+                recall: 0,
+                instant: false,
+                blocked: VaultError::InvalidAmount as u32,
+            };
+        }
+
+        let (fees, deposits) = exit_amounts(&e, &id);
+        let payout = fees + deposits;
+
+        let idle = get_idle_balance(&e);
+        let recall = if has_strategy(&e) && payout > idle {
+            payout - idle
+        } else {
+            0
+        };
+
+        // same checks as withdraw, in the same order
+        let blocked = if let Some(error) = withdraw_request_error(&e, id) {
+            Some(error)
+        } else if exceeds_max_withdraw(&e, payout) {
+            Some(VaultError::WithdrawLimit)
+        } else if get_liquidity(&e) < payout {
+            Some(VaultError::InsufficientLiquidity)
+        } else {
+            None
+        };
+
+        WithdSim {
+            deposits,
+            fees,
+            payout,
+            recall,
+            instant: blocked.is_none(),
+            blocked: blocked.map_or(0, |error| error as u32),
+        }
+    }
+
+    fn set_mindep(e: Env, auth: Auth, amount: i128) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_mindep"),
+            (auth_id, auth.nonce, amount),
+        );
+
+        put_min_deposit(&e, amount);
+    }
+
+    fn set_cooldn(e: Env, auth: Auth, seconds: u64) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_cooldn"),
+            (auth_id, auth.nonce, seconds),
+        );
+
+        put_cooldown(&e, seconds);
+    }
+
+    fn req_withd(e: Env, auth: Auth, id: Identifier) {
+        check_signer(&e, &auth.sig, &id);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("req_withd"),
+            (id.clone(), auth.nonce),
+        );
+
+        e.storage()
+            .set(DataKey::WithdReq(id), e.ledger().timestamp());
+    }
+
+    fn shares(e: Env, id: Identifier) -> i128 {
+        get_user_shares(&e, id)
+    }
+
+    fn price_hist(e: Env, n: u32) -> Vec<PricePt> {
+        let hist = get_price_hist(&e);
+        let skip = if hist.len() > n { hist.len() - n } else { 0 };
+
+        let mut res = Vec::new(&e);
+        for point in hist.iter().skip(skip as usize) {
+            res.push_back(point.unwrap());
+        }
+
+        res
+    }
+
+    fn grant_role(e: Env, auth: Auth, id: Identifier, role: Role) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("grant_role"),
+            (auth_id, auth.nonce, id.clone(), role),
+        );
+
+        if id == read_administrator(&e) {
+            panic_with_error!(&e, VaultError::InvalidAdmin)
+        }
+
+        write_role(&e, id, role);
+    }
+
+    fn drop_role(e: Env, auth: Auth, id: Identifier) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("drop_role"),
+            (auth_id, auth.nonce, id.clone()),
+        );
+
+        if id == read_administrator(&e) {
+            panic_with_error!(&e, VaultError::InvalidAdmin)
+        }
+
+        e.storage().remove(DataKey::Role(id));
+    }
+
+    fn role(e: Env, id: Identifier) -> Option<Role> {
+        read_role(&e, id)
+    }
+
+    fn admin_earn(e: Env) -> Earnings {
+        let (crystallized, _) = withdraw_amounts(&e, &read_administrator(&e));
+
+        let pending = if has_strategy(&e) {
+            let accrued = strategy::Client::new(&e, get_strategy(&e)).accrued();
+            mul_div_floor(&e, accrued, get_perf_fee(&e) as i128, 10000)
+        } else {
+            0
+        };
+
+        Earnings(crystallized, pending)
+    }
+
+    fn set_deptok(e: Env, auth: Auth, token: BytesN<32>, converter: BytesN<32>) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_deptok"),
+            (auth_id, auth.nonce, token.clone(), converter.clone()),
+        );
+
+        if token == get_token_id(&e) {
+            remove_converter(&e);
+        } else {
+            put_converter(&e, token, converter);
+        }
+    }
+
+    fn dep_token(e: Env) -> BytesN<32> {
+        get_deposit_token(&e)
+    }
+
+    fn bal_assets(e: Env, id: Identifier) -> i128 {
+        amount_for_shares(&e, get_user_shares(&e, id))
+    }
+
+    fn solvency(e: Env) -> Solvency {
+        Solvency(get_total_assets(&e), get_tot_deposit(&e))
+    }
+
+    fn dep_for(e: Env, auth: Auth, payer: Identifier, recipient: Identifier, amount: i128) -> i128 {
+        check_signer(&e, &auth.sig, &payer);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("dep_for"),
+            (payer.clone(), auth.nonce, recipient.clone(), amount),
+        );
+
+        deposit_from(&e, &payer, recipient, amount, None)
+    }
+
+    fn lot_price(e: Env, id: Identifier, lot: u32) -> i128 {
+        let batch_ts = get_user_batches(&e, id.clone())
+            .get(lot)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::BatchNotFound))
+            .unwrap();
+        let batch: BatchObj = e
+            .storage()
+            .get(DataKey::Batch(BatchKey(id, batch_ts)))
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::BatchNotFound))
+            .unwrap();
+
+        mul_div_floor(&e, batch.deposit, PRICE_SCALE, batch.init_s)
+    }
+
+    fn approve(e: Env, auth: Auth, owner: Identifier, spender: Identifier, amount: i128) {
+        check_signer(&e, &auth.sig, &owner);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("approve"),
+            (owner.clone(), auth.nonce, spender.clone(), amount),
+        );
+
+        if amount < 0 {
+            panic_with_error!(&e, VaultError::InvalidAmount)
+        }
+
+        write_allowance(&e, owner, spender, amount);
+    }
+
+    fn allowance(e: Env, owner: Identifier, spender: Identifier) -> i128 {
+        read_allowance(&e, owner, spender)
+    }
+
+    fn xfer_from(
+        e: Env,
+        auth: Auth,
+        spender: Identifier,
+        from: Identifier,
+        to: Identifier,
+        amount: i128,
+    ) {
+        check_signer(&e, &auth.sig, &spender);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("xfer_from"),
+            (
+                spender.clone(),
+                auth.nonce,
+                from.clone(),
+                to.clone(),
+                amount,
+            ),
+        );
+
+        check_positive(&e, amount);
+
+        check_allowed(&e, to.clone());
+        spend_allowance(&e, from.clone(), spender, amount);
+        move_shares(&e, from, to, amount);
+    }
+
+    fn allow_exp(e: Env, auth: Auth, id: Identifier, expires: u64) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("allow_exp"),
+            (auth_id, auth.nonce, id.clone(), expires),
+        );
+
+        e.storage().set(DataKey::Allowed(id.clone()), expires);
+
+        let mut expiring = get_expiring(&e);
+        if !expiring.contains(&id) {
+            expiring.push_back(id);
+            e.storage().set(DataKey::AllowExp, expiring);
+        }
+    }
+
+    fn prune_exp(e: Env) -> u32 {
+        let now = e.ledger().timestamp();
+        let mut pruned: u32 = 0;
+        let mut expiring = Vec::new(&e);
+
+        for id_el in get_expiring(&e).iter() {
+            let id = id_el.unwrap();
+
+            // entries removed or made permanent since are just dropped from the list
+            match read_allowed(&e, id.clone()) {
+                Some(expires) if expires <= now => {
+                    e.storage().remove(DataKey::Allowed(id));
+                    pruned += 1;
+                }
+                Some(expires) if expires != u64::MAX => expiring.push_back(id),
+                _ => {}
+            }
+        }
+
+        e.storage().set(DataKey::AllowExp, expiring);
+        pruned
+    }
+
+    fn net_apy(e: Env, window: u64) -> i32 {
+        let hist = get_price_hist(&e);
+        let since = e.ledger().timestamp().saturating_sub(window);
+
+        let first = hist
+            .iter()
+            .map(|point| point.unwrap())
+            .find(|PricePt(ts, _)| *ts >= since);
+        let last = hist.last().map(|point| point.unwrap());
+
+        let (PricePt(start_ts, start_price), PricePt(end_ts, end_price)) = match (first, last) {
+            (Some(first), Some(last)) if last.0 > first.0 => (first, last),
+            _ => return 0,
+        };
+
+        let elapsed = (end_ts - start_ts) as i128;
+        let apy = mul_div_floor(
+            &e,
+            end_price - start_price,
+            10000 * SECONDS_PER_YEAR,
+            start_price * elapsed,
+        );
+
+        if apy > i32::MAX as i128 {
+            i32::MAX
+        } else if apy < i32::MIN as i128 {
+            i32::MIN
+        } else {
+            apy as i32
+        }
+    }
+
+    fn snapshot(e: Env, auth: Auth) -> u32 {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(&e, &auth.sig, symbol!("snapshot"), (auth_id, auth.nonce));
+
+        let snap_id = get_snapshot_id(&e) + 1;
+        e.storage().set(DataKey::SnapId, snap_id);
+
+        e.events().publish((symbol!("snapshot"),), snap_id);
+        snap_id
+    }
+
+    fn shares_at(e: Env, snap_id: u32, holder: Identifier) -> i128 {
+        if snap_id == 0 || snap_id > get_snapshot_id(&e) {
+            panic_with_error!(&e, VaultError::InvalidSnapshot)
+        }
+
+        // the first balance recorded from `snap_id` on is the one the holder had at `snap_id`,
+        // with none recorded the balance hasn't changed since
+        for recorded in get_holder_snaps(&e, holder.clone()).iter() {
+            let recorded = recorded.unwrap();
+            if recorded >= snap_id {
+                let key = DataKey::Snapshot(SnapKey(recorded, holder));
+                return e
+                    .storage()
+                    .get(key)
+                    .unwrap_or_else(|| panic_with_error!(&e, VaultError::NotFound))
+                    .unwrap();
             }
         }
 
-        let initial_deposit = e
+        get_user_shares(&e, holder)
+    }
+
+    fn set_unit(e: Env, auth: Auth, unit: i128) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_unit"),
+            (auth_id, auth.nonce, unit),
+        );
+
+        check_deposit_unit(&e, unit);
+
+        put_deposit_unit(&e, unit);
+    }
+
+    fn notify_rwd(e: Env, auth: Auth, token: BytesN<32>, amount: i128) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("notify_rwd"),
+            (auth_id.clone(), auth.nonce, token.clone(), amount),
+        );
+
+        if token == get_token_id(&e) {
+            panic_with_error!(&e, VaultError::InvalidToken)
+        }
+
+        check_positive(&e, amount);
+
+        let tot_supply = get_tot_supply(&e);
+        if tot_supply == 0 {
+            panic_with_error!(&e, VaultError::NoShares)
+        }
+
+        transfer_token_in_vault(&e, token.clone(), &auth_id, &amount);
+
+        let mut tokens = get_reward_tokens(&e);
+        if !tokens.contains(&token) {
+            tokens.push_back(token.clone());
+            e.storage().set(DataKey::RwdTokens, tokens);
+        }
+
+        // the rounding dust stays in the vault
+        let index = get_reward_index(&e, token.clone())
+            + mul_div_floor(&e, amount, REWARD_SCALE, tot_supply);
+        e.storage().set(DataKey::RwdIndex(token), index);
+    }
+
+    fn claim_rwd(e: Env, auth: Auth, holder: Identifier, token: BytesN<32>) -> i128 {
+        check_signer(&e, &auth.sig, &holder);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("claim_rwd"),
+            (holder.clone(), auth.nonce, token.clone()),
+        );
+
+        claim_reward(&e, token, holder)
+    }
+
+    fn rewards(e: Env, holder: Identifier, token: BytesN<32>) -> i128 {
+        pending_reward(&e, token, holder)
+    }
+
+    fn claim_all(e: Env, auth: Auth, holder: Identifier, tokens: Vec<BytesN<32>>) -> Vec<i128> {
+        check_signer(&e, &auth.sig, &holder);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("claim_all"),
+            (holder.clone(), auth.nonce, tokens.clone()),
+        );
+
+        let mut claimed = Vec::new(&e);
+        for token in tokens.iter() {
+            claimed.push_back(claim_reward(&e, token.unwrap(), holder.clone()));
+        }
+
+        claimed
+    }
+
+    fn set_maxwd(e: Env, auth: Auth, bps: u32) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_maxwd"),
+            (auth_id, auth.nonce, bps),
+        );
+
+        check_bps(&e, bps);
+
+        put_max_withdraw(&e, bps);
+    }
+
+    fn set_buffer(e: Env, auth: Auth, bps: u32) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("set_buffer"),
+            (auth_id, auth.nonce, bps),
+        );
+
+        check_bps(&e, bps);
+
+        put_buffer_bps(&e, bps);
+    }
+
+    fn accr_since(e: Env, id: Identifier) -> i128 {
+        let last_price: i128 = match e.storage().get(DataKey::LastPrice(id.clone())) {
+            Some(price) => price.unwrap(),
+            None => return 0,
+        };
+
+        mul_div_floor(
+            &e,
+            get_user_shares(&e, id),
+            share_price(&e) - last_price,
+            PRICE_SCALE,
+        )
+    }
+
+    fn prop_admin(e: Env, auth: Auth, candidate: Identifier) {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("prop_admin"),
+            (auth_id, auth.nonce, candidate.clone()),
+        );
+
+        e.storage().set(DataKey::PendAdmin, candidate);
+    }
+
+    fn accept_adm(e: Env, auth: Auth) {
+        let candidate: Identifier = e
             .storage()
-            .get::<DataKey, i128>(DataKey::InitialDep(to.clone()))
-            .unwrap()
+            .get(DataKey::PendAdmin)
+            .unwrap_or_else(|| panic_with_error!(&e, VaultError::NoPendingAdmin))
             .unwrap();
 
-        transfer(&e, &to, amount + initial_deposit);
-        amount
+        check_signer(&e, &auth.sig, &candidate);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("accept_adm"),
+            (candidate.clone(), auth.nonce),
+        );
+
+        // the previous admin hands over its role along with the admin slot
+        e.storage().remove(DataKey::Role(read_administrator(&e)));
+        e.storage().remove(DataKey::PendAdmin);
+
+        write_administrator(&e, candidate.clone());
+        write_role(&e, candidate, Role::Admin);
+    }
+
+    fn reconcile(e: Env, auth: Auth) -> i128 {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(&e, &auth.sig, symbol!("reconcile"), (auth_id, auth.nonce));
+
+        let tot_assets = get_total_assets(&e);
+        let gain = tot_assets - get_tracked(&e);
+        put_tracked(&e, tot_assets);
+
+        mint_perf_fee(&e, gain);
+        checkpoint_price(&e);
+
+        gain
+    }
+
+    fn is_init(e: Env) -> bool {
+        has_administrator(&e)
+    }
+
+    fn queue_wd(e: Env, auth: Auth, to: Identifier) -> i128 {
+        check_signer(&e, &auth.sig, &to);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(&e, &auth.sig, symbol!("queue_wd"), (to.clone(), auth.nonce));
+
+        let payout = exit_position(&e, to.clone(), false);
+
+        let WithdClaim(queued, claimable) = read_claim(&e, to.clone());
+        write_claim(&e, to.clone(), WithdClaim(queued + payout, claimable));
+        add_reserved(&e, payout, 0);
+
+        let mut queue = get_withdraw_queue(&e);
+        if !queue.contains(&to) {
+            queue.push_back(to);
+            e.storage().set(DataKey::WithdQueue, queue);
+        }
+
+        checkpoint_price(&e);
+        payout
+    }
+
+    fn process_q(e: Env, auth: Auth) -> u32 {
+        check_admin(&e, &auth.sig);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+
+        let auth_id = auth.sig.identifier(&e);
+        verify(&e, &auth.sig, symbol!("process_q"), (auth_id, auth.nonce));
+
+        // what the idle tokens don't cover of the queued claims is recalled from the strategy,
+        // as far as it can return it right away
+        let missing = get_reserved(&e) - get_matured(&e) - get_idle_balance(&e);
+        if has_strategy(&e) && missing > 0 {
+            let client = strategy::Client::new(&e, get_strategy(&e));
+            let recallable = client.available();
+            let recalled = if missing < recallable {
+                missing
+            } else {
+                recallable
+            };
+
+            if recalled > 0 {
+                client.withdraw(&recalled);
+            }
+        }
+
+        let mut available = get_idle_balance(&e);
+        let mut processed: u32 = 0;
+        let mut queue = get_withdraw_queue(&e);
+
+        while let Some(id) = queue.first() {
+            let id = id.unwrap();
+            let WithdClaim(queued, claimable) = read_claim(&e, id.clone());
+            if queued > available {
+                break;
+            }
+
+            write_claim(&e, id, WithdClaim(0, claimable + queued));
+            add_reserved(&e, 0, queued);
+            available -= queued;
+
+            queue.pop_front();
+            processed += 1;
+        }
+
+        e.storage().set(DataKey::WithdQueue, queue);
+        processed
+    }
+
+    fn claim_wd(e: Env, auth: Auth, to: Identifier) -> i128 {
+        check_signer(&e, &auth.sig, &to);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(&e, &auth.sig, symbol!("claim_wd"), (to.clone(), auth.nonce));
+
+        let WithdClaim(queued, claimable) = read_claim(&e, to.clone());
+        if claimable == 0 {
+            panic_with_error!(&e, VaultError::NothingToClaim)
+        }
+
+        write_claim(&e, to.clone(), WithdClaim(queued, 0));
+        add_reserved(&e, -claimable, -claimable);
+        transfer_token(&e, get_token_id(&e), &to, claimable);
+
+        claimable
+    }
+
+    fn wd_claim(e: Env, id: Identifier) -> WithdClaim {
+        read_claim(&e, id)
+    }
+
+    fn quote(e: Env) -> Quote {
+        Quote(
+            get_total_assets(&e),
+            get_tot_supply(&e),
+            share_price(&e),
+            get_perf_fee(&e),
+        )
+    }
+
+    fn dep_ref(e: Env, auth: Auth, from: Identifier, amount: i128, referral: BytesN<32>) -> i128 {
+        check_signer(&e, &auth.sig, &from);
+        verify_and_consume_nonce(&e, &auth.sig, auth.nonce);
+        verify(
+            &e,
+            &auth.sig,
+            symbol!("dep_ref"),
+            (from.clone(), auth.nonce, amount, referral.clone()),
+        );
+
+        deposit_from(&e, &from, from.clone(), amount, Some(referral))
+    }
+
+    fn ref_volume(e: Env, referral: BytesN<32>) -> i128 {
+        read_ref_volume(&e, referral)
+    }
+
+    fn shares_of(e: Env, ids: Vec<Identifier>) -> Vec<i128> {
+        if ids.len() > MAX_BATCH {
+            panic_with_error!(&e, VaultError::BatchTooLarge)
+        }
+
+        let mut shares = Vec::new(&e);
+        for id in ids.iter() {
+            shares.push_back(get_user_shares(&e, id.unwrap()));
+        }
+
+        shares
     }
 }
 
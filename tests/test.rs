@@ -14,11 +14,326 @@ mod vault {
     );
 }
 
+mod strategy {
+    use soroban_auth::{Identifier, Signature};
+    use soroban_sdk::{contractimpl, contracttype, BytesN, Env};
+
+    #[derive(Clone)]
+    #[contracttype]
+    pub enum DataKey {
+        TokenId,
+        Principal,
+        Haircut,
+    }
+
+    // holds whatever the vault deploys and sends it back on request. Tokens minted
+    // directly to the strategy count as yield until they're harvested. With a haircut
+    // set, that many tokens of each deposit are lost to the vault
+    pub struct MockStrategy;
+
+    #[contractimpl]
+    impl MockStrategy {
+        pub fn init(e: Env, token_id: BytesN<32>) {
+            e.storage().set(DataKey::TokenId, token_id);
+        }
+
+        pub fn set_haircut(e: Env, amount: i128) {
+            e.storage().set(DataKey::Haircut, amount);
+        }
+
+        // reports a loss of `amount` on the deployed funds
+        pub fn write_down(e: Env, amount: i128) {
+            put_principal(&e, principal(&e) - amount);
+        }
+
+        pub fn deposit(e: Env, amount: i128) {
+            let haircut: i128 = e.storage().get(DataKey::Haircut).unwrap_or(Ok(0)).unwrap();
+            put_principal(&e, principal(&e) + amount - haircut);
+        }
+
+        pub fn withdraw(e: Env, amount: i128) {
+            put_principal(&e, principal(&e) - amount);
+
+            let to = Signature::Invoker.identifier(&e);
+            token(&e).xfer(&Signature::Invoker, &0, &to, &amount);
+        }
+
+        pub fn balance(e: Env) -> i128 {
+            principal(&e)
+        }
+
+        pub fn harvest(e: Env) -> i128 {
+            let accrued = Self::accrued(e.clone());
+
+            let to = Signature::Invoker.identifier(&e);
+            token(&e).xfer(&Signature::Invoker, &0, &to, &accrued);
+            accrued
+        }
+
+        pub fn accrued(e: Env) -> i128 {
+            let held = token(&e).balance(&Identifier::Contract(e.get_current_contract()));
+            held - principal(&e)
+        }
+
+        pub fn available(e: Env) -> i128 {
+            let held = token(&e).balance(&Identifier::Contract(e.get_current_contract()));
+            if held < principal(&e) {
+                held
+            } else {
+                principal(&e)
+            }
+        }
+    }
+
+    fn token(e: &Env) -> super::token::Client {
+        let token_id: BytesN<32> = e.storage().get(DataKey::TokenId).unwrap().unwrap();
+        super::token::Client::new(e, token_id)
+    }
+
+    fn principal(e: &Env) -> i128 {
+        e.storage()
+            .get(DataKey::Principal)
+            .unwrap_or(Ok(0))
+            .unwrap()
+    }
+
+    fn put_principal(e: &Env, amount: i128) {
+        e.storage().set(DataKey::Principal, amount);
+    }
+}
+
+mod converter {
+    use soroban_auth::Signature;
+    use soroban_sdk::{contractimpl, contracttype, BytesN, Env};
+
+    #[derive(Clone)]
+    #[contracttype]
+    pub enum DataKey {
+        BaseToken,
+        Rate,
+    }
+
+    // pays out `rate` base tokens for each wrapper token it receives, out of the
+    // base tokens minted to it
+    pub struct MockConverter;
+
+    #[contractimpl]
+    impl MockConverter {
+        pub fn init(e: Env, base_token: BytesN<32>, rate: i128) {
+            e.storage().set(DataKey::BaseToken, base_token);
+            e.storage().set(DataKey::Rate, rate);
+        }
+
+        pub fn convert(e: Env, amount: i128) {
+            let base_token: BytesN<32> = e.storage().get(DataKey::BaseToken).unwrap().unwrap();
+            let rate: i128 = e.storage().get(DataKey::Rate).unwrap().unwrap();
+
+            let to = Signature::Invoker.identifier(&e);
+            super::token::Client::new(&e, base_token).xfer(
+                &Signature::Invoker,
+                &0,
+                &to,
+                &(amount * rate),
+            );
+        }
+    }
+}
+
 //use crate::{VaultContract, VaultContractClient};
+use core::cell::RefCell;
+use soroban_auth::testutils::ed25519;
 use soroban_auth::{Identifier, Signature};
-use soroban_sdk::testutils::Logger;
+use soroban_sdk::testutils::{Events, Logger};
 use soroban_sdk::testutils::{Ledger, LedgerInfo};
-use soroban_sdk::{log, testutils::Accounts, AccountId, BytesN, Env, IntoVal};
+use soroban_sdk::{
+    log, symbol, testutils::Accounts, vec, AccountId, BytesN, Env, IntoVal, RawVal, Vec,
+};
+
+fn set_ts(e: &Env, timestamp: u64) {
+    e.ledger().set(LedgerInfo {
+        timestamp,
+        protocol_version: 1,
+        sequence_number: 10,
+        network_passphrase: Default::default(),
+        base_reserve: 10,
+    });
+}
+
+fn create_token(e: &Env, admin: &AccountId, seed: u8) -> BytesN<32> {
+    let token_id = e.register_contract_token(&BytesN::from_array(e, &[seed; 32]));
+    let token = token::Client::new(e, &token_id);
+    // decimals, name, symbol don't matter in tests
+    token.init(
+        &Identifier::Account(admin.clone()),
+        &token::TokenMetadata {
+            name: "USD coin".into_val(e),
+            symbol: "USDC".into_val(e),
+            decimals: 7,
+        },
+    );
+
+    token_id
+}
+
+fn invoker_auth() -> vault::Auth {
+    vault::Auth {
+        sig: Signature::Invoker,
+        nonce: 0,
+    }
+}
+
+struct Setup {
+    e: Env,
+    token_admin: AccountId,
+    admin: AccountId,
+    token_id: BytesN<32>,
+    token: token::Client,
+    vault: vault::Client,
+    vault_contract_id: BytesN<32>,
+    vault_id: Identifier,
+    // everyone who may hold shares, and the strategy once one is set, for assert_invariants
+    holders: RefCell<Vec<Identifier>>,
+    strategy_id: RefCell<Option<BytesN<32>>>,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let e: Env = Default::default();
+        set_ts(&e, 1666359075);
+
+        let token_admin = e.accounts().generate();
+        let admin = e.accounts().generate();
+
+        let token_id = create_token(&e, &token_admin, 1);
+        let token = token::Client::new(&e, &token_id);
+
+        let vault_contract_id =
+            e.register_contract_wasm(&BytesN::from_array(&e, &[5; 32]), vault::WASM);
+        let vault = vault::Client::new(&e, &vault_contract_id);
+        let vault_id = Identifier::Contract(vault_contract_id.clone());
+
+        vault.initialize(&Identifier::Account(admin.clone()), &token_id);
+
+        // the admin is paid its fees in shares
+        let holders = RefCell::new(vec![&e, Identifier::Account(admin.clone())]);
+
+        Setup {
+            e,
+            token_admin,
+            admin,
+            token_id,
+            token,
+            vault,
+            vault_contract_id,
+            vault_id,
+            holders,
+            strategy_id: RefCell::new(None),
+        }
+    }
+
+    // generates a user holding `amount` tokens, all of them approved to the vault
+    fn user(&self, amount: i128) -> (AccountId, Identifier) {
+        let user = self.e.accounts().generate();
+        let user_id = self.fund(&user, amount);
+        self.holders.borrow_mut().push_back(user_id.clone());
+
+        (user, user_id)
+    }
+
+    // mints `amount` tokens to `account` and approves them to the vault
+    fn fund(&self, account: &AccountId, amount: i128) -> Identifier {
+        let id = Identifier::Account(account.clone());
+
+        self.token.with_source_account(&self.token_admin).mint(
+            &Signature::Invoker,
+            &0,
+            &id,
+            &amount,
+        );
+        self.token.with_source_account(account).approve(
+            &Signature::Invoker,
+            &0,
+            &self.vault_id,
+            &amount,
+        );
+
+        id
+    }
+
+    fn strategy(&self) -> (strategy::MockStrategyClient, Identifier) {
+        let strategy_contract_id = self.e.register_contract(
+            &BytesN::from_array(&self.e, &[6; 32]),
+            strategy::MockStrategy,
+        );
+        let strategy = strategy::MockStrategyClient::new(&self.e, &strategy_contract_id);
+        strategy.init(&self.token_id);
+
+        self.vault
+            .with_source_account(&self.admin)
+            .set_strat(&invoker_auth(), &strategy_contract_id);
+        *self.strategy_id.borrow_mut() = Some(strategy_contract_id.clone());
+
+        (strategy, Identifier::Contract(strategy_contract_id))
+    }
+
+    // simulates fees arriving in the vault
+    fn yield_fees(&self, amount: i128) {
+        self.token.with_source_account(&self.token_admin).mint(
+            &Signature::Invoker,
+            &0,
+            &self.vault_id,
+            &amount,
+        );
+    }
+
+    fn assert_invariants(&self) {
+        let strategy = self
+            .strategy_id
+            .borrow()
+            .clone()
+            .map(|id| strategy::MockStrategyClient::new(&self.e, &id));
+
+        assert_invariants(
+            &self.vault,
+            &self.token,
+            &self.vault_id,
+            &self.holders.borrow(),
+            strategy.as_ref(),
+        );
+    }
+}
+
+// accounting the vault must keep after every step:
+// - the shares of all the holders add up to the total supply
+// - the total assets are the vault's tokens plus what the strategy reports, minus what's
+//   owed to queued withdrawals
+// - the total assets cover the recorded deposits, as long as the strategy hasn't lost any
+fn assert_invariants(
+    vault: &vault::Client,
+    token: &token::Client,
+    vault_id: &Identifier,
+    holders: &Vec<Identifier>,
+    strategy: Option<&strategy::MockStrategyClient>,
+) {
+    let vault::Quote(assets, supply, _, _) = vault.quote();
+
+    let mut shares = 0;
+    let mut owed = 0;
+    for holder in holders.iter() {
+        let holder = holder.unwrap();
+        shares += vault.shares(&holder);
+
+        let vault::WithdClaim(queued, claimable) = vault.wd_claim(&holder);
+        owed += queued + claimable;
+    }
+    assert_eq!(shares, supply);
+
+    let deployed = strategy.map_or(0, |strategy| strategy.balance());
+    assert_eq!(assets, token.balance(vault_id) + deployed - owed);
+
+    let vault::Solvency(_, deposits) = vault.solvency();
+    assert!(assets >= deposits);
+}
 
 #[test]
 fn test() {
@@ -37,6 +352,7 @@ fn test() {
     let user2 = e.accounts().generate();
     let user1_id = Identifier::Account(user1.clone());
     let user2_id = Identifier::Account(user2.clone());
+    let holders = vec![&e, user1_id.clone(), user2_id.clone()];
 
     let token_id = e.register_contract_token(&BytesN::from_array(
         &e,
@@ -85,11 +401,14 @@ fn test() {
     //    log!(&e, "depositing");
 
     // user1 buys shares from the vault
-    vault_client.deposit(&user1_id, &500);
+    vault_client
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
 
     extern crate std;
 
     assert_eq!(usdc_token.balance(&user1_id), 500);
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     let batch = vault_client.get_shares(&user1_id, &1666359075);
     std::println!("{:?}", batch.curr_s);
@@ -102,7 +421,13 @@ fn test() {
         base_reserve: 10,
     });
 
-    vault_client.fee_withd(&user1_id, &1666359075, &500);
+    vault_client.with_source_account(&user1).fee_withd(
+        &invoker_auth(),
+        &user1_id,
+        &1666359075,
+        &500,
+    );
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     assert_eq!(usdc_token.balance(&user1_id), 500);
 
@@ -117,9 +442,12 @@ fn test() {
         base_reserve: 10,
     });
 
-    vault_client.deposit(&user2_id, &1000);
+    vault_client
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &1000);
 
     assert_eq!(usdc_token.balance(&user2_id), 0);
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     let batch = vault_client.get_shares(&user2_id, &1767369075);
     std::println!("{:?}", batch.curr_s);
@@ -134,7 +462,13 @@ fn test() {
 
     std::println!("balance: {:?}", usdc_token.balance(&vault_id));
 
-    vault_client.fee_withd(&user2_id, &1767369075, &1000);
+    vault_client.with_source_account(&user2).fee_withd(
+        &invoker_auth(),
+        &user2_id,
+        &1767369075,
+        &1000,
+    );
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     let batch = vault_client.get_shares(&user2_id, &1867369075);
     std::println!(
@@ -168,7 +502,13 @@ fn test() {
 
     //    vault_client.fee_withd(&user1_id, &1667369075, &5);
 
-    vault_client.fee_withd(&user2_id, &1867369075, &500);
+    vault_client.with_source_account(&user2).fee_withd(
+        &invoker_auth(),
+        &user2_id,
+        &1867369075,
+        &500,
+    );
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     let batch = vault_client.get_shares(&user2_id, &1867369075);
 
@@ -263,10 +603,16 @@ fn test() {
 
     std::println!(
         "vault u2 withdraw all fees result: {:?}",
-        vault_client.withdraw(&user2_id)
+        vault_client
+            .with_source_account(&user2)
+            .withdraw(&invoker_auth(), &user2_id)
     );
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
-    vault_client.withdraw(&user1_id);
+    vault_client
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert_invariants(&vault_client, &usdc_token, &vault_id, &holders, None);
 
     /*    std::println!(
         "vault u1 withdraw all fees result: {:?}",
@@ -333,3 +679,2618 @@ fn test() {
     ); // user 1 now has 1001 USDC and still has 2 shares in the vault.
     assert_eq!(vault_client.get_shares(&user1_id), 2 as i128);*/
 }
+
+#[test]
+fn test_pending_fees() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    assert_eq!(s.vault.pend_fees(), 0);
+
+    s.yield_fees(100);
+    set_ts(&s.e, 1667369075);
+
+    assert_eq!(s.vault.pend_fees(), 100);
+    assert_eq!(s.token.balance(&s.vault_id), 600);
+}
+
+#[test]
+fn test_sweep_foreign_token() {
+    let s = Setup::new();
+    let (_user, user_id) = s.user(0);
+
+    let foreign_id = create_token(&s.e, &s.token_admin, 2);
+    let foreign = token::Client::new(&s.e, &foreign_id);
+    foreign
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &s.vault_id, &50);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .sweep(&invoker_auth(), &foreign_id, &user_id);
+
+    assert_eq!(foreign.balance(&s.vault_id), 0);
+    assert_eq!(foreign.balance(&user_id), 50);
+}
+
+#[test]
+fn test_sweep_vault_asset() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    let res =
+        s.vault
+            .with_source_account(&s.admin)
+            .try_sweep(&invoker_auth(), &s.token_id, &user_id);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&s.vault_id), 500);
+}
+
+#[test]
+fn test_sweep_not_admin() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(0);
+
+    let foreign_id = create_token(&s.e, &s.token_admin, 2);
+
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_sweep(&invoker_auth(), &foreign_id, &user_id);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_total_assets() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    assert_eq!(s.vault.tot_assets(), 0);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    s.yield_fees(100);
+
+    assert_eq!(s.vault.tot_assets(), 600);
+    assert_eq!(s.vault.tot_assets(), s.token.balance(&s.vault_id));
+}
+
+#[test]
+fn test_whole_shares() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &499);
+    s.yield_fees(100); // with the virtual share a share is now worth 600 / 500 = 1.2 tokens
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_whole(&invoker_auth(), &true);
+
+    set_ts(&s.e, 1667369075);
+    assert!(s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &100)
+        .is_err());
+    assert_eq!(s.token.balance(&user2_id), 1000);
+
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &120);
+    assert_eq!(s.vault.get_shares(&user2_id, &1667369075).curr_s, 100);
+}
+
+#[test]
+fn test_strategy_deposit_withdraw() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let (strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+    assert_eq!(s.token.balance(&strategy_id), 500);
+    assert_eq!(strategy.balance(), 500);
+    assert_eq!(s.vault.tot_assets(), 500);
+
+    set_ts(&s.e, 1667369075);
+    s.vault
+        .with_source_account(&user)
+        .withdraw(&invoker_auth(), &user_id);
+
+    assert_eq!(s.token.balance(&strategy_id), 0);
+    assert_eq!(s.token.balance(&user_id), 1000);
+    assert_eq!(s.vault.tot_assets(), 0);
+}
+
+#[test]
+fn test_strategy_recall_on_replace() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let (_strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    let other_contract_id =
+        s.e.register_contract(&BytesN::from_array(&s.e, &[7; 32]), strategy::MockStrategy);
+    strategy::MockStrategyClient::new(&s.e, &other_contract_id).init(&s.token_id);
+    s.vault
+        .with_source_account(&s.admin)
+        .set_strat(&invoker_auth(), &other_contract_id);
+
+    assert_eq!(s.token.balance(&strategy_id), 0);
+    assert_eq!(s.token.balance(&s.vault_id), 500);
+    assert_eq!(s.vault.tot_assets(), 500);
+}
+
+#[test]
+fn test_harvest() {
+    let s = Setup::new();
+    let admin_id = Identifier::Account(s.admin.clone());
+    let (user, user_id) = s.user(1000);
+    let (_strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_perf(&invoker_auth(), &1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    // the strategy accrues yield
+    s.token
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &strategy_id, &100);
+    assert_eq!(s.vault.tot_assets(), 500);
+
+    set_ts(&s.e, 1667369075);
+    let realized = s
+        .vault
+        .with_source_account(&s.admin)
+        .harvest(&invoker_auth());
+
+    assert_eq!(realized, 100);
+    assert_eq!(s.vault.tot_assets(), 600);
+    assert_eq!(s.token.balance(&s.vault_id), 100);
+
+    // 10% of the yield: 10 * 501 / (600 - 10 + 1)
+    let fee_batch = s.vault.get_shares(&admin_id, &1667369075);
+    assert_eq!(fee_batch.curr_s, 8);
+    assert_eq!(fee_batch.deposit, 0);
+}
+
+#[test]
+fn test_residual_after_full_exit() {
+    let s = Setup::new();
+    let admin_id = Identifier::Account(s.admin.clone());
+    let (user1, user1_id) = s.user(500);
+    let (user2, user2_id) = s.user(500);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    set_ts(&s.e, 1667369075);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert_eq!(s.token.balance(&user1_id), 500);
+
+    // tokens are left in the vault with no shares outstanding
+    s.yield_fees(50);
+
+    set_ts(&s.e, 1767369075);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+
+    assert_eq!(s.vault.get_shares(&user2_id, &1767369075).curr_s, 500);
+    assert_eq!(s.vault.get_shares(&admin_id, &1767369075).curr_s, 50);
+
+    // the residual was already credited, it isn't a gain to take a fee on
+    let admin_vault = s.vault.with_source_account(&s.admin);
+    admin_vault.set_perf(&invoker_auth(), &1000);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 0);
+    assert_eq!(s.vault.shares(&admin_id), 50);
+
+    set_ts(&s.e, 1867369075);
+    s.vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+
+    assert_eq!(s.token.balance(&user2_id), 500);
+    assert_eq!(s.token.balance(&s.vault_id), 50);
+}
+
+#[test]
+fn test_project_position() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &499);
+    s.yield_fees(100); // with the virtual share a share is now worth 600 / 500 = 1.2 tokens
+
+    assert_eq!(s.vault.proj_pos(&120, &0, &0), 120);
+    assert_eq!(s.vault.proj_pos(&120, &1000, &365), 132);
+    assert_eq!(s.vault.proj_pos(&120, &1000, &730), 144);
+    assert_eq!(s.vault.proj_pos(&120, &2000, &365), 144);
+    assert!(s.vault.proj_pos(&120, &1000, &100) > s.vault.proj_pos(&120, &1000, &10));
+}
+
+#[test]
+fn test_allowlist() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let admin = s.vault.with_source_account(&s.admin);
+
+    admin.set_allow(&invoker_auth(), &true);
+    admin.allow(&invoker_auth(), &user1_id);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    assert_eq!(s.token.balance(&user1_id), 500);
+
+    assert!(s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &500)
+        .is_err());
+    assert_eq!(s.token.balance(&user2_id), 1000);
+
+    admin.disallow(&invoker_auth(), &user1_id);
+    set_ts(&s.e, 1667369075);
+    assert!(s
+        .vault
+        .with_source_account(&user1)
+        .try_deposit(&invoker_auth(), &user1_id, &500)
+        .is_err());
+
+    // with the allowlist disabled anyone can deposit again
+    admin.set_allow(&invoker_auth(), &false);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+    assert_eq!(s.token.balance(&user2_id), 500);
+}
+
+#[test]
+fn test_zero_boundaries() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    // getters on an empty vault read zero rather than failing
+    assert_eq!(s.vault.nonce(), 0);
+    assert_eq!(s.vault.tot_assets(), 0);
+    assert_eq!(s.vault.pend_fees(), 0);
+
+    // the first deposit into an empty vault mints 1:1
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    assert_eq!(s.vault.get_shares(&user_id, &1666359075).curr_s, 500);
+
+    // invoker auth only accepts a zero nonce
+    let res = s.vault.with_source_account(&s.admin).try_set_whole(
+        &vault::Auth {
+            sig: Signature::Invoker,
+            nonce: 1,
+        },
+        &true,
+    );
+    assert!(res.is_err());
+    assert_eq!(s.vault.nonce(), 0);
+}
+
+#[test]
+fn test_deposit_batch() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (_user2, user2_id) = s.user(0);
+    let (_user3, user3_id) = s.user(0);
+    let (_user4, user4_id) = s.user(0);
+    let admin_id = s.fund(&s.admin, 420);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.yield_fees(100); // a share is now worth about 1.2 tokens
+
+    set_ts(&s.e, 1667369075);
+    s.vault.with_source_account(&s.admin).dep_batch(
+        &invoker_auth(),
+        &vec![
+            &s.e,
+            vault::DepEntry(user2_id.clone(), 120),
+            vault::DepEntry(user3_id.clone(), 240),
+            vault::DepEntry(user4_id.clone(), 60),
+        ],
+    );
+
+    assert_eq!(s.token.balance(&admin_id), 0);
+    assert_eq!(s.vault.tot_assets(), 1020);
+    assert_eq!(s.vault.get_shares(&user2_id, &1667369075).curr_s, 100);
+    assert_eq!(s.vault.get_shares(&user3_id, &1667369075).curr_s, 200);
+    assert_eq!(s.vault.get_shares(&user4_id, &1667369075).curr_s, 50);
+}
+
+#[test]
+fn test_deposit_batch_price_drop() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (_user2, user2_id) = s.user(0);
+    let admin_id = s.fund(&s.admin, 300);
+    let (strategy, _strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+
+    // the strategy loses part of what's deployed, diluting user1
+    strategy.set_haircut(&100);
+    set_ts(&s.e, 1667369075);
+    let res = s.vault.with_source_account(&s.admin).try_dep_batch(
+        &invoker_auth(),
+        &vec![&s.e, vault::DepEntry(user2_id.clone(), 300)],
+    );
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::PriceDrop.into())));
+    assert_eq!(s.token.balance(&admin_id), 300);
+    assert_eq!(s.vault.shares(&user2_id), 0);
+}
+
+#[test]
+fn test_deposit_batch_invalid_entry() {
+    let s = Setup::new();
+    let (_user1, user1_id) = s.user(0);
+    let (_user2, user2_id) = s.user(0);
+    let admin_id = s.fund(&s.admin, 500);
+
+    let res = s.vault.with_source_account(&s.admin).try_dep_batch(
+        &invoker_auth(),
+        &vec![
+            &s.e,
+            vault::DepEntry(user1_id.clone(), 500),
+            vault::DepEntry(user2_id.clone(), 0),
+        ],
+    );
+
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&admin_id), 500);
+    assert_eq!(s.vault.batches(&user1_id).len(), 0);
+}
+
+#[test]
+fn test_deposit_withdraw_auth() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    // user2 can't deposit user1's approved tokens nor exit user1's position
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user1_id, &500);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user1_id), 1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+
+    set_ts(&s.e, 1667369075);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_withdraw(&invoker_auth(), &user1_id);
+    assert!(res.is_err());
+    assert_eq!(s.vault.batches(&user1_id).len(), 1);
+
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert_eq!(s.token.balance(&user1_id), 1000);
+    assert_eq!(s.token.balance(&user2_id), 1000);
+}
+
+#[test]
+fn test_withdraw_whole_position() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    let shares = s
+        .vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    assert_eq!(shares, 500);
+
+    set_ts(&s.e, 1667369075);
+    let shares = s
+        .vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &300);
+    assert_eq!(shares, 300);
+
+    s.yield_fees(80);
+
+    set_ts(&s.e, 1767369075);
+    let payout = s
+        .vault
+        .with_source_account(&user)
+        .withdraw(&invoker_auth(), &user_id);
+
+    // the only holder is the last one out and takes the rounding dust along
+    assert_eq!(payout, 1080);
+    assert_eq!(s.token.balance(&user_id), 1080);
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+    assert_eq!(s.vault.batches(&user_id).len(), 0);
+
+    // nothing is left to withdraw
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_withdraw(&invoker_auth(), &user_id);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user_id), 1080);
+}
+
+#[test]
+fn test_update_config() {
+    let s = Setup::new();
+    let admin = s.vault.with_source_account(&s.admin);
+
+    let config = vault::VaultConfig {
+        perf_fee: 2000,
+        whole_shrs: true,
+        allowlist: true,
+        max_withd: 5000,
+        min_dep: 100,
+        cooldown: 1000,
+        dep_unit: 10,
+        buffer_bps: 2500,
+        dep_paused: true,
+    };
+    admin.upd_config(&invoker_auth(), &config);
+
+    let assert_applied = || {
+        let stored = s.vault.config();
+        assert_eq!(stored.perf_fee, 2000);
+        assert!(stored.whole_shrs);
+        assert!(stored.allowlist);
+        assert_eq!(stored.max_withd, 5000);
+        assert_eq!(stored.min_dep, 100);
+        assert_eq!(stored.cooldown, 1000);
+        assert_eq!(stored.dep_unit, 10);
+        assert_eq!(stored.buffer_bps, 2500);
+        assert!(stored.dep_paused);
+    };
+    assert_applied();
+
+    // any invalid setting rejects the whole bundle
+    let invalid = [
+        (
+            vault::VaultConfig {
+                perf_fee: 10001,
+                ..config.clone()
+            },
+            vault::VaultError::InvalidBps,
+        ),
+        (
+            vault::VaultConfig {
+                max_withd: 10001,
+                ..config.clone()
+            },
+            vault::VaultError::InvalidBps,
+        ),
+        (
+            vault::VaultConfig {
+                buffer_bps: 10001,
+                ..config.clone()
+            },
+            vault::VaultError::InvalidBps,
+        ),
+        (
+            vault::VaultConfig {
+                dep_unit: 0,
+                ..config.clone()
+            },
+            vault::VaultError::InvalidDepositUnit,
+        ),
+        (
+            vault::VaultConfig {
+                min_dep: -1,
+                ..config.clone()
+            },
+            vault::VaultError::InvalidAmount,
+        ),
+    ];
+    for (config, error) in invalid {
+        let res = admin.try_upd_config(
+            &invoker_auth(),
+            &vault::VaultConfig {
+                whole_shrs: false,
+                allowlist: false,
+                dep_paused: false,
+                ..config
+            },
+        );
+        assert_eq!(res.err(), Some(Ok(error.into())));
+        assert_applied();
+    }
+}
+
+#[test]
+fn test_simulate_withdraw() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.yield_fees(100);
+
+    // all idle, with fees
+    set_ts(&s.e, 1667369075);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert_eq!(sim.deposits, 500);
+    assert_eq!(sim.fees, 100);
+    assert_eq!(sim.payout, 600);
+    assert_eq!(sim.recall, 0);
+
+    let payout = s
+        .vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert_eq!(payout, sim.payout);
+    assert_eq!(payout - sim.deposits, sim.fees);
+    assert_eq!(s.token.balance(&user1_id), 500 + sim.payout);
+
+    // deployed to a strategy, no fees
+    let (_strategy, strategy_id) = s.strategy();
+    set_ts(&s.e, 1767369075);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &400);
+
+    let sim = s.vault.sim_withd(&user2_id);
+    assert_eq!(sim.deposits, 400);
+    assert_eq!(sim.fees, 0);
+    assert_eq!(sim.payout, 400);
+    assert_eq!(sim.recall, 400);
+
+    set_ts(&s.e, 1867369075);
+    s.vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    assert_eq!(s.token.balance(&user2_id), 600 + sim.payout);
+    assert_eq!(s.token.balance(&strategy_id), 0);
+}
+
+#[test]
+fn test_simulate_blocked_withdraw() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (strategy, strategy_id) = s.strategy();
+    let code = |error: vault::VaultError| error as u32;
+
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(!sim.instant);
+    assert_eq!(sim.blocked, code(vault::VaultError::InvalidAmount));
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &600);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &400);
+
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(sim.instant);
+    assert_eq!(sim.blocked, 0);
+
+    // cooldown
+    s.vault
+        .with_source_account(&s.admin)
+        .set_cooldn(&invoker_auth(), &1000);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(!sim.instant);
+    assert_eq!(sim.blocked, code(vault::VaultError::NoWithdrawRequest));
+
+    s.vault
+        .with_source_account(&user1)
+        .req_withd(&invoker_auth(), &user1_id);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert_eq!(sim.blocked, code(vault::VaultError::CooldownActive));
+
+    set_ts(&s.e, 1666360075);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(sim.instant);
+
+    // per-transaction limit
+    s.vault
+        .with_source_account(&s.admin)
+        .set_maxwd(&invoker_auth(), &5000);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(!sim.instant);
+    assert_eq!(sim.blocked, code(vault::VaultError::WithdrawLimit));
+    s.vault
+        .with_source_account(&s.admin)
+        .set_maxwd(&invoker_auth(), &0);
+
+    // part of the deployed funds get stuck in the strategy, the withdrawal has to be queued
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &strategy_id, &500);
+    assert_eq!(strategy.available(), 500);
+    let sim = s.vault.sim_withd(&user1_id);
+    assert!(!sim.instant);
+    assert_eq!(sim.blocked, code(vault::VaultError::InsufficientLiquidity));
+
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_withdraw(&invoker_auth(), &user1_id);
+    assert_eq!(
+        res.err(),
+        Some(Ok(vault::VaultError::InsufficientLiquidity.into()))
+    );
+    s.vault
+        .with_source_account(&user1)
+        .queue_wd(&invoker_auth(), &user1_id);
+}
+
+#[test]
+fn test_min_deposit() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_mindep(&invoker_auth(), &100);
+
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &50);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user_id), 1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &100);
+    assert_eq!(s.token.balance(&user_id), 900);
+}
+
+#[test]
+fn test_zero_shares_deposit() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.yield_fees(500); // a share is now worth 2 tokens
+
+    set_ts(&s.e, 1667369075);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &1);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user2_id), 1000);
+}
+
+#[test]
+fn test_withdraw_cooldown() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_cooldn(&invoker_auth(), &1000);
+
+    vault.deposit(&invoker_auth(), &user_id, &500);
+
+    set_ts(&s.e, 1666360000);
+    assert!(vault.try_withdraw(&invoker_auth(), &user_id).is_err());
+
+    vault.req_withd(&invoker_auth(), &user_id);
+
+    set_ts(&s.e, 1666360500);
+    assert!(vault.try_withdraw(&invoker_auth(), &user_id).is_err());
+    assert_eq!(s.token.balance(&user_id), 500);
+
+    set_ts(&s.e, 1666361000);
+    vault.withdraw(&invoker_auth(), &user_id);
+    assert_eq!(s.token.balance(&user_id), 1000);
+}
+
+#[test]
+fn test_fee_withdraw_cooldown() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_cooldn(&invoker_auth(), &1000);
+
+    vault.deposit(&invoker_auth(), &user_id, &500);
+    s.yield_fees(100);
+
+    // the fee path goes through the same cooldown as withdraw
+    set_ts(&s.e, 1666360000);
+    let res = vault.try_fee_withd(&invoker_auth(), &user_id, &1666359075, &500);
+    assert!(res.is_err());
+
+    vault.req_withd(&invoker_auth(), &user_id);
+
+    set_ts(&s.e, 1666360500);
+    let res = vault.try_fee_withd(&invoker_auth(), &user_id, &1666359075, &500);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user_id), 500);
+
+    // the virtual share keeps its cut of the fees
+    set_ts(&s.e, 1666361000);
+    vault.fee_withd(&invoker_auth(), &user_id, &1666359075, &500);
+    assert_eq!(s.token.balance(&user_id), 599);
+}
+
+#[test]
+fn test_balance_events() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user);
+
+    let balance_event = |balance: i128| {
+        let topics: soroban_sdk::Vec<RawVal> = (symbol!("balance"), user_id.clone()).into_val(&s.e);
+        (s.vault_contract_id.clone(), topics, balance.into_val(&s.e))
+    };
+
+    vault.deposit(&invoker_auth(), &user_id, &500);
+    let events = s.e.events().all();
+    assert!(events.iter().any(|ev| ev.unwrap() == balance_event(500)));
+    assert_eq!(s.vault.shares(&user_id), 500);
+
+    set_ts(&s.e, 1667369075);
+    vault.withdraw(&invoker_auth(), &user_id);
+    let events = s.e.events().all();
+    assert!(events.iter().any(|ev| ev.unwrap() == balance_event(0)));
+    assert_eq!(s.vault.shares(&user_id), 0);
+}
+
+#[test]
+fn test_price_history() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (user3, user3_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &499);
+
+    s.yield_fees(100);
+    set_ts(&s.e, 1667369075);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &120);
+
+    s.yield_fees(72);
+    set_ts(&s.e, 1767369075);
+    s.vault
+        .with_source_account(&user3)
+        .deposit(&invoker_auth(), &user3_id, &132);
+
+    let hist = s.vault.price_hist(&10);
+    assert_eq!(hist.len(), 3);
+    assert_eq!(
+        hist.get(0).unwrap().unwrap(),
+        vault::PricePt(1666359075, 10000000)
+    );
+    assert_eq!(
+        hist.get(1).unwrap().unwrap(),
+        vault::PricePt(1667369075, 12000000)
+    );
+    assert_eq!(
+        hist.get(2).unwrap().unwrap(),
+        vault::PricePt(1767369075, 13200000)
+    );
+
+    let hist = s.vault.price_hist(&2);
+    assert_eq!(hist.len(), 2);
+    assert_eq!(
+        hist.get(0).unwrap().unwrap(),
+        vault::PricePt(1667369075, 12000000)
+    );
+}
+
+#[test]
+fn test_roles() {
+    let s = Setup::new();
+    let admin_id = Identifier::Account(s.admin.clone());
+    let (operator, operator_id) = s.user(0);
+    let (user, user_id) = s.user(1000);
+    s.strategy();
+
+    assert_eq!(s.vault.role(&admin_id), Some(vault::Role::Admin));
+    assert_eq!(s.vault.role(&operator_id), None);
+
+    s.vault.with_source_account(&s.admin).grant_role(
+        &invoker_auth(),
+        &operator_id,
+        &vault::Role::Operator,
+    );
+    assert_eq!(s.vault.role(&operator_id), Some(vault::Role::Operator));
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    // an operator can harvest but can't change fees
+    s.vault
+        .with_source_account(&operator)
+        .harvest(&invoker_auth());
+    let res = s
+        .vault
+        .with_source_account(&operator)
+        .try_set_perf(&invoker_auth(), &1000);
+    assert!(res.is_err());
+
+    s.vault
+        .with_source_account(&s.admin)
+        .drop_role(&invoker_auth(), &operator_id);
+    assert_eq!(s.vault.role(&operator_id), None);
+
+    set_ts(&s.e, 1667369075);
+    let res = s
+        .vault
+        .with_source_account(&operator)
+        .try_harvest(&invoker_auth());
+    assert!(res.is_err());
+
+    // the initial admin can't lose its role, nor be downgraded by another admin
+    let res = s
+        .vault
+        .with_source_account(&s.admin)
+        .try_drop_role(&invoker_auth(), &admin_id);
+    assert!(res.is_err());
+
+    s.vault.with_source_account(&s.admin).grant_role(
+        &invoker_auth(),
+        &operator_id,
+        &vault::Role::Admin,
+    );
+    let res = s.vault.with_source_account(&operator).try_grant_role(
+        &invoker_auth(),
+        &admin_id,
+        &vault::Role::Operator,
+    );
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::InvalidAdmin.into())));
+    assert_eq!(s.vault.role(&admin_id), Some(vault::Role::Admin));
+}
+
+#[test]
+fn test_signed_nonce_replay() {
+    let s = Setup::new();
+    let (user_id, user_sign) = ed25519::generate(&s.e);
+
+    s.token
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &user_id, &1000);
+    let approve_sig = ed25519::sign(
+        &s.e,
+        &user_sign,
+        &s.token_id,
+        symbol!("approve"),
+        (&user_id, &0_i128, &s.vault_id, &1000_i128),
+    );
+    s.token.approve(&approve_sig, &0, &s.vault_id, &1000);
+
+    let deposit_auth = |nonce: i128, amount: i128| vault::Auth {
+        sig: ed25519::sign(
+            &s.e,
+            &user_sign,
+            &s.vault_contract_id,
+            symbol!("deposit"),
+            (&user_id, &nonce, &amount),
+        ),
+        nonce,
+    };
+
+    let auth = deposit_auth(0, 100);
+    s.vault.deposit(&auth, &user_id, &100);
+
+    // the same signed deposit can't be submitted twice
+    set_ts(&s.e, 1667369075);
+    assert!(s.vault.try_deposit(&auth, &user_id, &100).is_err());
+    assert_eq!(s.token.balance(&user_id), 900);
+
+    s.vault.deposit(&deposit_auth(1, 100), &user_id, &100);
+    assert_eq!(s.token.balance(&user_id), 800);
+
+    let auth = vault::Auth {
+        sig: ed25519::sign(
+            &s.e,
+            &user_sign,
+            &s.vault_contract_id,
+            symbol!("withdraw"),
+            (&user_id, &2_i128),
+        ),
+        nonce: 2,
+    };
+    set_ts(&s.e, 1767369075);
+    s.vault.withdraw(&auth, &user_id);
+    assert_eq!(s.token.balance(&user_id), 1000);
+
+    // a replayed withdraw is rejected rather than paying out nothing
+    let res = s.vault.try_withdraw(&auth, &user_id);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_admin_earnings() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let (_strategy, strategy_id) = s.strategy();
+    let mint_yield = |amount: i128| {
+        s.token.with_source_account(&s.token_admin).mint(
+            &Signature::Invoker,
+            &0,
+            &strategy_id,
+            &amount,
+        )
+    };
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_perf(&invoker_auth(), &1000);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    assert_eq!(s.vault.admin_earn(), vault::Earnings(0, 0));
+
+    // 8 fee shares worth 8 * 601 / 509 are crystallized
+    mint_yield(100);
+    set_ts(&s.e, 1667369075);
+    s.vault
+        .with_source_account(&s.admin)
+        .harvest(&invoker_auth());
+
+    // 10% of the yield accrued since is pending
+    mint_yield(50);
+    assert_eq!(s.vault.admin_earn(), vault::Earnings(9, 5));
+}
+
+#[test]
+fn test_overflow_boundary() {
+    // small enough for the price checkpoint (assets * 10^7) to fit in an i128
+    const BIG: i128 = i128::MAX / 20000000;
+
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(BIG);
+    let (user2, user2_id) = s.user(BIG);
+
+    // the first deposit mints 1:1
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &BIG);
+    assert_eq!(s.vault.get_shares(&user1_id, &1666359075).curr_s, BIG);
+
+    // amount * supply doesn't fit in an i128 and must not wrap around
+    set_ts(&s.e, 1667369075);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &BIG);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&user2_id), BIG);
+}
+
+#[test]
+fn test_rounding_favors_vault() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user2);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    s.yield_fees(333);
+
+    let mut ts = 1666359075;
+    let mut price = s.vault.price_hist(&1).get(0).unwrap().unwrap().1;
+
+    // round trips of odd amounts, split across two batches, never take out more
+    // than was put in and never lower the price per share
+    for i in 1..20 {
+        ts += 1;
+        set_ts(&s.e, ts);
+        vault.deposit(&invoker_auth(), &user2_id, &(7 * i));
+
+        ts += 1;
+        set_ts(&s.e, ts);
+        vault.deposit(&invoker_auth(), &user2_id, &(3 * i + 1));
+
+        ts += 1;
+        set_ts(&s.e, ts);
+        vault.withdraw(&invoker_auth(), &user2_id);
+
+        assert!(s.token.balance(&user2_id) <= 1000);
+
+        let new_price = s.vault.price_hist(&1).get(0).unwrap().unwrap().1;
+        assert!(new_price >= price);
+        price = new_price;
+    }
+
+    // the holder who stayed is still owed at least its full share
+    ts += 1;
+    set_ts(&s.e, ts);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert!(s.token.balance(&user1_id) >= 1333);
+}
+
+#[test]
+fn test_wrapped_deposit() {
+    let s = Setup::new();
+
+    let wrapper_id = create_token(&s.e, &s.token_admin, 2);
+    let wrapper = token::Client::new(&s.e, &wrapper_id);
+
+    let converter_contract_id = s.e.register_contract(
+        &BytesN::from_array(&s.e, &[8; 32]),
+        converter::MockConverter,
+    );
+    let converter_id = Identifier::Contract(converter_contract_id.clone());
+    converter::MockConverterClient::new(&s.e, &converter_contract_id).init(&s.token_id, &2);
+    s.token
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &converter_id, &1000);
+
+    s.vault.with_source_account(&s.admin).set_deptok(
+        &invoker_auth(),
+        &wrapper_id,
+        &converter_contract_id,
+    );
+    assert_eq!(s.vault.dep_token(), wrapper_id);
+
+    let user = s.e.accounts().generate();
+    let user_id = Identifier::Account(user.clone());
+    wrapper
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &user_id, &100);
+    wrapper
+        .with_source_account(&user)
+        .approve(&Signature::Invoker, &0, &s.vault_id, &100);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &100);
+
+    // 100 wrapper tokens are worth 200 of the vault's token
+    assert_eq!(wrapper.balance(&user_id), 0);
+    assert_eq!(wrapper.balance(&converter_id), 100);
+    assert_eq!(s.token.balance(&s.vault_id), 200);
+    assert_eq!(s.vault.shares(&user_id), 200);
+    assert_eq!(s.vault.tot_assets(), 200);
+
+    // withdrawals pay out in the vault's token
+    s.vault
+        .with_source_account(&user)
+        .withdraw(&invoker_auth(), &user_id);
+    assert_eq!(s.token.balance(&user_id), 200);
+
+    s.vault.with_source_account(&s.admin).set_deptok(
+        &invoker_auth(),
+        &s.token_id,
+        &converter_contract_id,
+    );
+    assert_eq!(s.vault.dep_token(), s.token_id);
+}
+
+#[test]
+fn test_balance_of_assets() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &1000);
+    assert_eq!(s.vault.bal_assets(&user_id), 1000);
+
+    // the virtual share keeps its cut of the yield
+    s.yield_fees(100);
+    assert!(s.vault.bal_assets(&user_id) > 1000);
+    assert_eq!(s.vault.bal_assets(&user_id), 1099);
+}
+
+#[test]
+fn test_solvency() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &1000);
+    s.yield_fees(50);
+
+    let vault::Solvency(assets, required) = s.vault.solvency();
+    assert_eq!(assets, 1050);
+    assert_eq!(required, 1000);
+    assert!(assets >= required);
+
+    // the vault loses more than the yield it earned
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &s.vault_id, &150);
+
+    let vault::Solvency(assets, required) = s.vault.solvency();
+    assert_eq!(assets, 900);
+    assert_eq!(required, 1000);
+    assert!(assets < required);
+}
+
+#[test]
+fn test_deposit_batch_too_large() {
+    let s = Setup::new();
+    let (_user, user_id) = s.user(0);
+    let admin_id = s.fund(&s.admin, 1000);
+
+    let mut entries = vec![&s.e];
+    for _ in 0..51 {
+        entries.push_back(vault::DepEntry(user_id.clone(), 10));
+    }
+
+    let res = s
+        .vault
+        .with_source_account(&s.admin)
+        .try_dep_batch(&invoker_auth(), &entries);
+
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&admin_id), 1000);
+
+    // exactly at the limit goes through
+    entries.pop_back();
+    s.vault
+        .with_source_account(&s.admin)
+        .dep_batch(&invoker_auth(), &entries);
+
+    assert_eq!(s.token.balance(&admin_id), 500);
+    assert_eq!(s.vault.shares(&user_id), 500);
+}
+
+#[test]
+fn test_deposit_for() {
+    let s = Setup::new();
+    let (payer, payer_id) = s.user(1000);
+    let (_recipient, recipient_id) = s.user(0);
+
+    let shares = s.vault.with_source_account(&payer).dep_for(
+        &invoker_auth(),
+        &payer_id,
+        &recipient_id,
+        &400,
+    );
+
+    assert_eq!(shares, 400);
+    assert_eq!(s.token.balance(&payer_id), 600);
+    assert_eq!(s.vault.shares(&payer_id), 0);
+    assert_eq!(s.vault.shares(&recipient_id), 400);
+    assert_eq!(s.vault.batches(&payer_id).len(), 0);
+    assert_eq!(s.vault.batches(&recipient_id).len(), 1);
+
+    // the payer has to be the signer
+    let res = s.vault.with_source_account(&payer).try_dep_for(
+        &invoker_auth(),
+        &recipient_id,
+        &recipient_id,
+        &100,
+    );
+    assert!(res.is_err());
+
+    // the payer holds no shares to withdraw
+    let res = s
+        .vault
+        .with_source_account(&payer)
+        .try_withdraw(&invoker_auth(), &payer_id);
+    assert!(res.is_err());
+    assert_eq!(s.token.balance(&payer_id), 600);
+}
+
+#[test]
+fn test_lot_price() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &400);
+
+    // the share price doubles before the second lot
+    s.yield_fees(400);
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &400);
+
+    assert_eq!(s.vault.lot_price(&user_id, &0), 20000000);
+    assert_eq!(s.vault.lot_price(&user_id, &1), 10000000);
+    assert!(s.vault.try_lot_price(&user_id, &2).is_err());
+}
+
+#[test]
+fn test_share_allowance() {
+    let s = Setup::new();
+    let (owner, owner_id) = s.user(1000);
+    let (spender, spender_id) = s.user(0);
+    let (_to, to_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&owner)
+        .deposit(&invoker_auth(), &owner_id, &1000);
+
+    // nothing can be moved before an approval
+    let res = s.vault.with_source_account(&spender).try_xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &100,
+    );
+    assert!(res.is_err());
+
+    s.vault
+        .with_source_account(&owner)
+        .approve(&invoker_auth(), &owner_id, &spender_id, &300);
+    assert_eq!(s.vault.allowance(&owner_id, &spender_id), 300);
+
+    s.vault.with_source_account(&spender).xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &200,
+    );
+    assert_eq!(s.vault.allowance(&owner_id, &spender_id), 100);
+    assert_eq!(s.vault.shares(&owner_id), 800);
+    assert_eq!(s.vault.shares(&to_id), 200);
+
+    // the rest of the allowance isn't enough
+    let res = s.vault.with_source_account(&spender).try_xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &150,
+    );
+    assert!(res.is_err());
+    assert_eq!(s.vault.shares(&to_id), 200);
+
+    // revoking leaves nothing to spend
+    s.vault
+        .with_source_account(&owner)
+        .approve(&invoker_auth(), &owner_id, &spender_id, &0);
+    assert_eq!(s.vault.allowance(&owner_id, &spender_id), 0);
+
+    let res = s.vault.with_source_account(&spender).try_xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &50,
+    );
+    assert!(res.is_err());
+
+    // the moved shares carry their deposit, both sides withdraw what they hold
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&owner)
+        .withdraw(&invoker_auth(), &owner_id);
+    assert_eq!(s.token.balance(&owner_id), 800);
+}
+
+#[test]
+fn test_prune_expired_allowlist() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (_user2, user2_id) = s.user(1000);
+    let (_user3, user3_id) = s.user(1000);
+
+    let admin_vault = s.vault.with_source_account(&s.admin);
+    admin_vault.set_allow(&invoker_auth(), &true);
+    admin_vault.allow_exp(&invoker_auth(), &user1_id, &1666359175);
+    admin_vault.allow_exp(&invoker_auth(), &user2_id, &1666369075);
+    admin_vault.allow(&invoker_auth(), &user3_id);
+
+    // nothing has expired yet
+    assert_eq!(s.vault.prune_exp(), 0);
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &100);
+
+    set_ts(&s.e, 1666359175);
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_deposit(&invoker_auth(), &user1_id, &100);
+    assert!(res.is_err());
+
+    // anyone can prune, only the expired entry goes away
+    let (anyone, _) = s.user(0);
+    assert_eq!(s.vault.with_source_account(&anyone).prune_exp(), 1);
+    assert_eq!(s.vault.prune_exp(), 0);
+
+    set_ts(&s.e, 1666369075);
+    assert_eq!(s.vault.prune_exp(), 1);
+    assert_eq!(s.vault.prune_exp(), 0);
+}
+
+#[test]
+fn test_net_apy() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(2000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &999);
+
+    // a single checkpoint isn't enough
+    assert_eq!(s.vault.net_apy(&31536000), 0);
+
+    // 10% over a tenth of a year, checkpointed by a deposit at the new price
+    s.yield_fees(100);
+    set_ts(&s.e, 1666359075 + 3153600);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &110);
+
+    assert_eq!(s.vault.net_apy(&31536000), 10000);
+
+    // the window only covers the latest checkpoint
+    assert_eq!(s.vault.net_apy(&100), 0);
+}
+
+#[test]
+fn test_snapshots() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+
+    let snap1 = s
+        .vault
+        .with_source_account(&s.admin)
+        .snapshot(&invoker_auth());
+    assert_eq!(snap1, 1);
+
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &300);
+
+    let snap2 = s
+        .vault
+        .with_source_account(&s.admin)
+        .snapshot(&invoker_auth());
+
+    set_ts(&s.e, 1666359275);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &200);
+
+    assert_eq!(s.vault.shares_at(&snap1, &user1_id), 1000);
+    assert_eq!(s.vault.shares_at(&snap1, &user2_id), 500);
+    assert_eq!(s.vault.shares_at(&snap2, &user1_id), 0);
+    assert_eq!(s.vault.shares_at(&snap2, &user2_id), 800);
+    assert_eq!(s.vault.shares(&user2_id), 1000);
+
+    assert!(s.vault.try_shares_at(&3, &user2_id).is_err());
+    assert!(s
+        .vault
+        .with_source_account(&user1)
+        .try_snapshot(&invoker_auth())
+        .is_err());
+}
+
+#[test]
+fn test_deposit_unit() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    // any amount goes by default
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &7);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_unit(&invoker_auth(), &100);
+
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &150);
+    assert!(res.is_err());
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &200);
+    assert_eq!(s.token.balance(&user_id), 793);
+
+    let res = s
+        .vault
+        .with_source_account(&s.admin)
+        .try_set_unit(&invoker_auth(), &0);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_rewards() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(3000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &3000);
+
+    let reward_id = create_token(&s.e, &s.token_admin, 2);
+    let reward = token::Client::new(&s.e, &reward_id);
+    let admin_id = Identifier::Account(s.admin.clone());
+    reward
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &admin_id, &400);
+    reward
+        .with_source_account(&s.admin)
+        .approve(&Signature::Invoker, &0, &s.vault_id, &400);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .notify_rwd(&invoker_auth(), &reward_id, &400);
+    assert_eq!(s.vault.rewards(&user1_id, &reward_id), 100);
+    assert_eq!(s.vault.rewards(&user2_id, &reward_id), 300);
+
+    // shares minted after the distribution don't earn from it
+    let (user3, user3_id) = s.user(1000);
+    s.vault
+        .with_source_account(&user3)
+        .deposit(&invoker_auth(), &user3_id, &1000);
+    assert_eq!(s.vault.rewards(&user3_id, &reward_id), 0);
+
+    // leaving the vault keeps what was accrued
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+
+    let claimed =
+        s.vault
+            .with_source_account(&user1)
+            .claim_rwd(&invoker_auth(), &user1_id, &reward_id);
+    assert_eq!(claimed, 100);
+    s.vault
+        .with_source_account(&user2)
+        .claim_rwd(&invoker_auth(), &user2_id, &reward_id);
+
+    assert_eq!(reward.balance(&user1_id), 100);
+    assert_eq!(reward.balance(&user2_id), 300);
+    assert_eq!(reward.balance(&s.vault_id), 0);
+    assert_eq!(s.vault.rewards(&user2_id, &reward_id), 0);
+
+    // the reward token can't be swept out from under the holders
+    let res =
+        s.vault
+            .with_source_account(&s.admin)
+            .try_sweep(&invoker_auth(), &reward_id, &admin_id);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_claim_all_rewards() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let admin_id = Identifier::Account(s.admin.clone());
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &1000);
+
+    let reward1_id = create_token(&s.e, &s.token_admin, 2);
+    let reward2_id = create_token(&s.e, &s.token_admin, 3);
+    for (reward_id, amount) in [(&reward1_id, 200), (&reward2_id, 50)] {
+        let reward = token::Client::new(&s.e, reward_id);
+        reward.with_source_account(&s.token_admin).mint(
+            &Signature::Invoker,
+            &0,
+            &admin_id,
+            &amount,
+        );
+        reward
+            .with_source_account(&s.admin)
+            .approve(&Signature::Invoker, &0, &s.vault_id, &amount);
+
+        s.vault
+            .with_source_account(&s.admin)
+            .notify_rwd(&invoker_auth(), reward_id, &amount);
+    }
+
+    let claimed = s.vault.with_source_account(&user1).claim_all(
+        &invoker_auth(),
+        &user1_id,
+        &vec![&s.e, reward1_id.clone(), reward2_id.clone()],
+    );
+
+    assert_eq!(claimed, vec![&s.e, 100, 25]);
+    assert_eq!(
+        token::Client::new(&s.e, &reward1_id).balance(&user1_id),
+        100
+    );
+    assert_eq!(token::Client::new(&s.e, &reward2_id).balance(&user1_id), 25);
+    assert_eq!(s.vault.rewards(&user1_id, &reward1_id), 0);
+    assert_eq!(s.vault.rewards(&user1_id, &reward2_id), 0);
+    assert_eq!(s.vault.rewards(&user2_id, &reward2_id), 25);
+}
+
+#[test]
+fn test_initialize_guards() {
+    let e: Env = Default::default();
+    let token_admin = e.accounts().generate();
+    let admin_id = Identifier::Account(e.accounts().generate());
+    let token_id = create_token(&e, &token_admin, 1);
+
+    let vault_contract_id =
+        e.register_contract_wasm(&BytesN::from_array(&e, &[9; 32]), vault::WASM);
+    let vault = vault::Client::new(&e, &vault_contract_id);
+    let vault_id = Identifier::Contract(vault_contract_id.clone());
+
+    // the vault can't account in its own id nor administer itself
+    assert!(vault.try_initialize(&admin_id, &vault_contract_id).is_err());
+    assert!(vault.try_initialize(&vault_id, &token_id).is_err());
+
+    let zero_key = Identifier::Ed25519(BytesN::from_array(&e, &[0; 32]));
+    assert!(vault.try_initialize(&zero_key, &token_id).is_err());
+
+    // the failed attempts left the vault uninitialized
+    vault.initialize(&admin_id, &token_id);
+    assert_eq!(vault.role(&admin_id), Some(vault::Role::Admin));
+    assert!(vault.try_initialize(&admin_id, &token_id).is_err());
+}
+
+#[test]
+fn test_max_withdraw() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(600);
+    let (user2, user2_id) = s.user(400);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &600);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &400);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_maxwd(&invoker_auth(), &5000);
+
+    // 600 out of 1000 is over the limit
+    set_ts(&s.e, 1666359175);
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_withdraw(&invoker_auth(), &user1_id);
+    assert!(res.is_err());
+    assert_eq!(s.vault.shares(&user1_id), 600);
+
+    let payout = s
+        .vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    assert_eq!(payout, 400);
+
+    // disabling the cap lets the rest out
+    s.vault
+        .with_source_account(&s.admin)
+        .set_maxwd(&invoker_auth(), &0);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    assert_eq!(s.token.balance(&user1_id), 600);
+}
+
+#[test]
+fn test_max_fee_withdraw() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user);
+
+    vault.deposit(&invoker_auth(), &user_id, &500);
+    s.yield_fees(100);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_maxwd(&invoker_auth(), &1000);
+
+    // 99 of fees out of 600 is over the 10% limit, the virtual share keeps the rest
+    set_ts(&s.e, 1666359175);
+    let res = vault.try_fee_withd(&invoker_auth(), &user_id, &1666359075, &500);
+    assert!(res.is_err());
+
+    // the fees of a smaller part of the batch fit
+    vault.fee_withd(&invoker_auth(), &user_id, &1666359075, &250);
+    assert_eq!(s.token.balance(&user_id), 549);
+}
+
+#[test]
+fn test_accrued_since_last() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(2000);
+    assert_eq!(s.vault.accr_since(&user_id), 0);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &1000);
+    // the virtual share keeps its cut of the yield
+    s.yield_fees(100);
+    assert_eq!(s.vault.accr_since(&user_id), 99);
+
+    // depositing again starts over from the current price
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &110);
+    assert_eq!(s.vault.accr_since(&user_id), 0);
+
+    s.yield_fees(110);
+    assert_eq!(s.vault.accr_since(&user_id), 109);
+}
+
+#[test]
+fn test_admin_handoff() {
+    let s = Setup::new();
+    let admin_id = Identifier::Account(s.admin.clone());
+    let (candidate, candidate_id) = s.user(0);
+    let (other, _other_id) = s.user(0);
+
+    // nothing to accept yet
+    let res = s
+        .vault
+        .with_source_account(&candidate)
+        .try_accept_adm(&invoker_auth());
+    assert!(res.is_err());
+
+    s.vault
+        .with_source_account(&s.admin)
+        .prop_admin(&invoker_auth(), &candidate_id);
+
+    // proposing alone doesn't hand over control
+    assert_eq!(s.vault.role(&candidate_id), None);
+    assert_eq!(s.vault.role(&admin_id), Some(vault::Role::Admin));
+
+    let res = s
+        .vault
+        .with_source_account(&other)
+        .try_accept_adm(&invoker_auth());
+    assert!(res.is_err());
+    assert_eq!(s.vault.role(&admin_id), Some(vault::Role::Admin));
+
+    s.vault
+        .with_source_account(&candidate)
+        .accept_adm(&invoker_auth());
+    assert_eq!(s.vault.role(&candidate_id), Some(vault::Role::Admin));
+    assert_eq!(s.vault.role(&admin_id), None);
+
+    // the new admin is in charge, the old one is out
+    s.vault
+        .with_source_account(&candidate)
+        .set_mindep(&invoker_auth(), &10);
+    let res = s
+        .vault
+        .with_source_account(&s.admin)
+        .try_set_mindep(&invoker_auth(), &0);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_reconcile() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+
+    // deposits are tracked, they aren't gains
+    let admin_vault = s.vault.with_source_account(&s.admin);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 0);
+
+    s.yield_fees(150);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 150);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 0);
+
+    // payouts aren't losses either
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 0);
+
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_reconcile(&invoker_auth());
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_perf_fee_on_profit_only() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let admin_id = Identifier::Account(s.admin.clone());
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &1000);
+
+    let admin_vault = s.vault.with_source_account(&s.admin);
+    admin_vault.set_perf(&invoker_auth(), &1000);
+
+    // flat: no fee
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 0);
+    assert_eq!(s.vault.shares(&admin_id), 0);
+
+    // 10% gain: a 10 token fee, the shares minted for it are rounded down
+    s.yield_fees(100);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), 100);
+    assert_eq!(s.vault.shares(&admin_id), 9);
+    assert_eq!(s.vault.bal_assets(&admin_id), 9);
+
+    // down: no fee either
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &s.vault_id, &50);
+    assert_eq!(admin_vault.reconcile(&invoker_auth()), -50);
+    assert_eq!(s.vault.shares(&admin_id), 9);
+}
+
+#[test]
+fn test_is_initialized() {
+    let e: Env = Default::default();
+    let token_admin = e.accounts().generate();
+    let admin_id = Identifier::Account(e.accounts().generate());
+    let token_id = create_token(&e, &token_admin, 1);
+
+    let vault_contract_id =
+        e.register_contract_wasm(&BytesN::from_array(&e, &[9; 32]), vault::WASM);
+    let vault = vault::Client::new(&e, &vault_contract_id);
+    assert!(!vault.is_init());
+
+    vault.initialize(&admin_id, &token_id);
+    assert!(vault.is_init());
+}
+
+#[test]
+fn test_withdraw_queue() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(500);
+    let (user2, user2_id) = s.user(500);
+    let (strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+
+    set_ts(&s.e, 1666359175);
+    let queued = s
+        .vault
+        .with_source_account(&user1)
+        .queue_wd(&invoker_auth(), &user1_id);
+    assert_eq!(queued, 500);
+    assert_eq!(s.vault.shares(&user1_id), 0);
+    assert_eq!(s.vault.wd_claim(&user1_id), vault::WithdClaim(500, 0));
+
+    // the queued assets don't back the remaining shares
+    assert_eq!(s.vault.tot_assets(), 500);
+
+    // part of the deployed funds get stuck in the strategy, what it can return isn't enough
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &strategy_id, &700);
+    let admin_vault = s.vault.with_source_account(&s.admin);
+    assert_eq!(admin_vault.process_q(&invoker_auth()), 0);
+    assert_eq!(s.token.balance(&s.vault_id), 300);
+    assert_eq!(s.vault.wd_claim(&user1_id), vault::WithdClaim(500, 0));
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_claim_wd(&invoker_auth(), &user1_id);
+    assert!(res.is_err());
+
+    // once the strategy can return the rest, only what's missing is recalled
+    s.token
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &strategy_id, &700);
+    assert_eq!(admin_vault.process_q(&invoker_auth()), 1);
+    assert_eq!(s.vault.wd_claim(&user1_id), vault::WithdClaim(0, 500));
+    assert_eq!(s.token.balance(&s.vault_id), 500);
+    assert_eq!(strategy.balance(), 500);
+
+    let claimed = s
+        .vault
+        .with_source_account(&user1)
+        .claim_wd(&invoker_auth(), &user1_id);
+    assert_eq!(claimed, 500);
+    assert_eq!(s.token.balance(&user1_id), 500);
+    assert_eq!(s.vault.wd_claim(&user1_id), vault::WithdClaim(0, 0));
+
+    s.vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    assert_eq!(s.token.balance(&user2_id), 500);
+    assert_eq!(s.vault.tot_assets(), 0);
+    assert_eq!(strategy.balance(), 0);
+}
+
+#[test]
+fn test_zero_amounts_rejected() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let (other, other_id) = s.user(0);
+
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &0);
+    assert!(res.is_err());
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &-5);
+    assert!(res.is_err());
+    assert_eq!(s.vault.batches(&user_id).len(), 0);
+
+    // a withdraw with no shares doesn't burn a nonce on a no-op
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_withdraw(&invoker_auth(), &user_id);
+    assert!(res.is_err());
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+    s.vault
+        .with_source_account(&user)
+        .approve(&invoker_auth(), &user_id, &other_id, &100);
+
+    let res = s.vault.with_source_account(&other).try_xfer_from(
+        &invoker_auth(),
+        &other_id,
+        &user_id,
+        &other_id,
+        &0,
+    );
+    assert!(res.is_err());
+    assert_eq!(s.vault.allowance(&user_id, &other_id), 100);
+    assert_eq!(s.token.balance(&user_id), 500);
+}
+
+#[test]
+fn test_quote() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    // an empty vault quotes the 1:1 initial price
+    assert_eq!(s.vault.quote(), vault::Quote(0, 0, 10000000, 0));
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &800);
+    s.yield_fees(200);
+    s.vault
+        .with_source_account(&s.admin)
+        .set_perf(&invoker_auth(), &1500);
+
+    let vault::Quote(assets, supply, price, fee_bps) = s.vault.quote();
+    assert_eq!(assets, 1000);
+    assert_eq!(supply, 800);
+    assert_eq!(price, (assets + 1) * 10000000 / (supply + 1));
+    assert_eq!(fee_bps, 1500);
+}
+
+#[test]
+fn test_withdraw_insufficient_liquidity() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(500);
+    let (strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    // part of the deployed funds get stuck in the strategy
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &strategy_id, &200);
+    assert_eq!(strategy.available(), 300);
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+
+    set_ts(&s.e, 1666359175);
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_withdraw(&invoker_auth(), &user_id);
+    assert!(res.is_err());
+
+    assert_eq!(s.vault.shares(&user_id), 500);
+    assert_eq!(s.vault.batches(&user_id).len(), 1);
+    assert_eq!(s.token.balance(&user_id), 0);
+}
+
+#[test]
+fn test_referral_volume() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let ref_a = BytesN::from_array(&s.e, &[1; 32]);
+    let ref_b = BytesN::from_array(&s.e, &[2; 32]);
+
+    s.vault
+        .with_source_account(&user1)
+        .dep_ref(&invoker_auth(), &user1_id, &300, &ref_a);
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user1)
+        .dep_ref(&invoker_auth(), &user1_id, &200, &ref_a);
+    s.vault
+        .with_source_account(&user2)
+        .dep_ref(&invoker_auth(), &user2_id, &400, &ref_b);
+
+    // plain deposits aren't attributed to anyone
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &100);
+
+    assert_eq!(s.vault.ref_volume(&ref_a), 500);
+    assert_eq!(s.vault.ref_volume(&ref_b), 400);
+    assert_eq!(s.vault.shares(&user1_id), 500);
+
+    // every deposit is published, a referred one carries the tag
+    let deposit_event = |amount: i128, referral: Option<BytesN<32>>| {
+        let topics: soroban_sdk::Vec<RawVal> =
+            (symbol!("deposit"), user2_id.clone()).into_val(&s.e);
+        (
+            s.vault_contract_id.clone(),
+            topics,
+            (amount, referral).into_val(&s.e),
+        )
+    };
+    let events = s.e.events().all();
+    assert!(events
+        .iter()
+        .any(|ev| ev.unwrap() == deposit_event(400, Some(ref_b.clone()))));
+    assert!(events
+        .iter()
+        .any(|ev| ev.unwrap() == deposit_event(100, None)));
+}
+
+#[test]
+fn test_price_manipulation_guard() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (strategy, _strategy_id) = s.strategy();
+
+    // a regular deposit after a donation goes through
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.yield_fees(50);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &330);
+    assert_eq!(s.vault.shares(&user2_id), 300);
+
+    // assets lost while depositing would dilute the existing holders
+    strategy.set_haircut(&100);
+    set_ts(&s.e, 1666359175);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &330);
+    assert!(res.is_err());
+    assert_eq!(s.vault.shares(&user2_id), 300);
+    assert_eq!(s.token.balance(&user2_id), 670);
+}
+
+#[test]
+fn test_shares_of() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (_user3, user3_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &200);
+
+    let shares = s.vault.shares_of(&vec![
+        &s.e,
+        user1_id.clone(),
+        user3_id.clone(),
+        user2_id.clone(),
+    ]);
+    assert_eq!(
+        shares,
+        vec![
+            &s.e,
+            s.vault.shares(&user1_id),
+            s.vault.shares(&user3_id),
+            s.vault.shares(&user2_id)
+        ]
+    );
+    assert_eq!(shares, vec![&s.e, 500, 0, 200]);
+
+    let mut ids = vec![&s.e];
+    for _ in 0..51 {
+        ids.push_back(user1_id.clone());
+    }
+    assert!(s.vault.try_shares_of(&ids).is_err());
+}
+
+#[test]
+fn test_loss_socialized_on_withdraw() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (user3, user3_id) = s.user(1000);
+    let (strategy, strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &333);
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user3)
+        .deposit(&invoker_auth(), &user3_id, &167);
+
+    // the strategy loses 20% of the deployed funds
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &strategy_id, &200);
+    strategy.write_down(&200);
+    assert_eq!(s.vault.tot_assets(), 800);
+
+    set_ts(&s.e, 1666359275);
+    let paid1 = s
+        .vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+    let paid2 = s
+        .vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    let paid3 = s
+        .vault
+        .with_source_account(&user3)
+        .withdraw(&invoker_auth(), &user3_id);
+
+    // everyone takes the same 20% cut, the last one out also gets the rounding dust
+    assert_eq!(paid1, 266);
+    assert_eq!(paid2, 400);
+    assert_eq!(paid3, 134);
+    s.assert_invariants();
+
+    assert_eq!(s.vault.tot_assets(), 0);
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+    assert_eq!(strategy.balance(), 0);
+    assert_eq!(s.vault.quote(), vault::Quote(0, 0, 10000000, 0));
+}
+
+#[test]
+fn test_virtual_offset() {
+    // the first depositor donates right after depositing to round the next depositor down
+    let s = Setup::new();
+    let (attacker, attacker_id) = s.user(1000);
+    let (victim, victim_id) = s.user(1500);
+
+    s.vault
+        .with_source_account(&attacker)
+        .deposit(&invoker_auth(), &attacker_id, &1);
+    s.yield_fees(999);
+    s.vault
+        .with_source_account(&victim)
+        .deposit(&invoker_auth(), &victim_id, &1500);
+
+    // without an offset the victim would get as many shares as the attacker: 1500 * 1 / 1000.
+    // The virtual share and asset make it 1500 * (1 + 1) / (1000 + 1)
+    let (attacker_shares, assets_before) = (1, 1000);
+    let shares_without_offset = 1500 * attacker_shares / assets_before;
+    assert_eq!(shares_without_offset, 1);
+    assert_eq!(s.vault.shares(&victim_id), 2);
+
+    set_ts(&s.e, 1666359175);
+    let paid = s
+        .vault
+        .with_source_account(&attacker)
+        .withdraw(&invoker_auth(), &attacker_id);
+
+    // without an offset the attacker's share would be worth half of the 2500 assets, a profit
+    // on the 1000 put in. With it the virtual share takes its cut, 2501 * 1 / (3 + 1), and the
+    // attack loses money
+    let paid_without_offset = 2500 * attacker_shares / (attacker_shares + shares_without_offset);
+    assert_eq!(paid_without_offset, 1250);
+    assert_eq!(paid, 625);
+    assert!(paid < 1000);
+
+    // the victim is the last one out and gets what the attacker left behind
+    let paid = s
+        .vault
+        .with_source_account(&victim)
+        .withdraw(&invoker_auth(), &victim_id);
+    assert_eq!(paid, 1875);
+}
+
+#[test]
+fn test_pause_deposits() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &500);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .pause_dep(&invoker_auth(), &true);
+
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &100);
+    assert!(res.is_err());
+
+    // withdrawals are still open
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user)
+        .withdraw(&invoker_auth(), &user_id);
+    assert_eq!(s.token.balance(&user_id), 1000);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .pause_dep(&invoker_auth(), &false);
+    s.vault
+        .with_source_account(&user)
+        .deposit(&invoker_auth(), &user_id, &100);
+    assert_eq!(s.vault.shares(&user_id), 100);
+
+    // holders without a role can't pause
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_pause_dep(&invoker_auth(), &true);
+    assert!(res.is_err());
+
+    // an operator can
+    let (operator, operator_id) = s.user(0);
+    s.vault.with_source_account(&s.admin).grant_role(
+        &invoker_auth(),
+        &operator_id,
+        &vault::Role::Operator,
+    );
+    s.vault
+        .with_source_account(&operator)
+        .pause_dep(&invoker_auth(), &true);
+    let res = s
+        .vault
+        .with_source_account(&user)
+        .try_deposit(&invoker_auth(), &user_id, &100);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_liquidity_buffer() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(500);
+    let (user3, user3_id) = s.user(100);
+    let (strategy, _strategy_id) = s.strategy();
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_buffer(&invoker_auth(), &2000);
+
+    // 20% of the total assets stays idle
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &1000);
+    assert_eq!(strategy.balance(), 800);
+    assert_eq!(s.token.balance(&s.vault_id), 200);
+
+    s.vault
+        .with_source_account(&user2)
+        .deposit(&invoker_auth(), &user2_id, &500);
+    assert_eq!(strategy.balance(), 1200);
+    assert_eq!(s.token.balance(&s.vault_id), 300);
+
+    s.vault
+        .with_source_account(&user3)
+        .deposit(&invoker_auth(), &user3_id, &100);
+    assert_eq!(strategy.balance(), 1280);
+    assert_eq!(s.token.balance(&s.vault_id), 320);
+    s.assert_invariants();
+
+    // a withdrawal the buffer covers leaves the strategy alone
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user3)
+        .withdraw(&invoker_auth(), &user3_id);
+    assert_eq!(s.token.balance(&user3_id), 100);
+    assert_eq!(strategy.balance(), 1280);
+    assert_eq!(s.token.balance(&s.vault_id), 220);
+
+    // a larger one recalls only what's missing
+    s.vault
+        .with_source_account(&user2)
+        .withdraw(&invoker_auth(), &user2_id);
+    assert_eq!(s.token.balance(&user2_id), 500);
+    assert_eq!(strategy.balance(), 1000);
+    assert_eq!(s.token.balance(&s.vault_id), 0);
+    s.assert_invariants();
+
+    let res = s
+        .vault
+        .with_source_account(&s.admin)
+        .try_set_buffer(&invoker_auth(), &10001);
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_error_codes() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, user2_id) = s.user(1000);
+    let (strategy, _strategy_id) = s.strategy();
+
+    let res = s
+        .vault
+        .with_source_account(&user1)
+        .try_set_whole(&invoker_auth(), &true);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::NotAuthorized.into())));
+
+    let res = s.vault.with_source_account(&s.admin).try_set_whole(
+        &vault::Auth {
+            sig: Signature::Invoker,
+            nonce: 1,
+        },
+        &true,
+    );
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::InvalidNonce.into())));
+
+    let res = s
+        .vault
+        .try_initialize(&Identifier::Account(s.admin.clone()), &s.token_id);
+    assert_eq!(
+        res.err(),
+        Some(Ok(vault::VaultError::AlreadyInitialized.into()))
+    );
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    let res = s.vault.with_source_account(&user1).try_fee_withd(
+        &invoker_auth(),
+        &user1_id,
+        &1666359075,
+        &501,
+    );
+    assert_eq!(
+        res.err(),
+        Some(Ok(vault::VaultError::InsufficientShares.into()))
+    );
+
+    // the haircut would dilute user1
+    strategy.set_haircut(&100);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &330);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::PriceDrop.into())));
+    strategy.set_haircut(&0);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .pause_dep(&invoker_auth(), &true);
+    let res = s
+        .vault
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &330);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::Paused.into())));
+
+    let res = s.vault.try_get_shares(&user2_id, &1666359075);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::BatchNotFound.into())));
+    let res =
+        s.vault
+            .with_source_account(&user1)
+            .try_fee_withd(&invoker_auth(), &user1_id, &1, &100);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::BatchNotFound.into())));
+
+    let res = s.vault.try_proj_pos(&i128::MAX, &10000, &365);
+    assert_eq!(res.err(), Some(Ok(vault::VaultError::Overflow.into())));
+
+    let uninit_id =
+        s.e.register_contract_wasm(&BytesN::from_array(&s.e, &[9; 32]), vault::WASM);
+    let uninit = vault::Client::new(&s.e, &uninit_id);
+    let res = uninit
+        .with_source_account(&user2)
+        .try_deposit(&invoker_auth(), &user2_id, &330);
+    assert_eq!(
+        res.err(),
+        Some(Ok(vault::VaultError::NotInitialized.into()))
+    );
+}
+
+#[test]
+fn test_fee_withdraw_guards() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(1000);
+    let (user2, _user2_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    s.yield_fees(100);
+
+    // only the holder can withdraw their batch's fees
+    let res = s.vault.with_source_account(&user2).try_fee_withd(
+        &invoker_auth(),
+        &user1_id,
+        &1666359075,
+        &500,
+    );
+    assert!(res.is_err());
+
+    let res = s.vault.with_source_account(&user1).try_fee_withd(
+        &invoker_auth(),
+        &user1_id,
+        &1666359075,
+        &-500,
+    );
+    assert!(res.is_err());
+    let res = s.vault.with_source_account(&user1).try_fee_withd(
+        &invoker_auth(),
+        &user1_id,
+        &1666359075,
+        &0,
+    );
+    assert!(res.is_err());
+
+    assert_eq!(s.token.balance(&user1_id), 500);
+    assert_eq!(s.vault.shares(&user1_id), 500);
+}
+
+#[test]
+fn test_accrued_after_share_transfer() {
+    let s = Setup::new();
+    let (owner, owner_id) = s.user(1000);
+    let (spender, spender_id) = s.user(0);
+    let (_to, to_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&owner)
+        .deposit(&invoker_auth(), &owner_id, &1000);
+    s.yield_fees(500);
+
+    s.vault
+        .with_source_account(&owner)
+        .approve(&invoker_auth(), &owner_id, &spender_id, &200);
+    s.vault.with_source_account(&spender).xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &200,
+    );
+
+    // moving shares doesn't change their price for either side
+    assert_eq!(s.vault.accr_since(&owner_id), 0);
+    assert_eq!(s.vault.accr_since(&to_id), 0);
+
+    s.yield_fees(100);
+    assert!(s.vault.accr_since(&owner_id) >= 0);
+    assert_eq!(s.vault.accr_since(&owner_id), 79);
+}
+
+#[test]
+fn test_merge_into_partly_burned_batch() {
+    let s = Setup::new();
+    let (owner, owner_id) = s.user(1480);
+    let (spender, spender_id) = s.user(0);
+    let (_to, to_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&owner)
+        .deposit(&invoker_auth(), &owner_id, &1000);
+    s.yield_fees(500);
+
+    // half of the batch moves out, then more is deposited in the same ledger
+    s.vault
+        .with_source_account(&owner)
+        .approve(&invoker_auth(), &owner_id, &spender_id, &500);
+    s.vault.with_source_account(&spender).xfer_from(
+        &invoker_auth(),
+        &spender_id,
+        &owner_id,
+        &to_id,
+        &500,
+    );
+    s.vault
+        .with_source_account(&owner)
+        .deposit(&invoker_auth(), &owner_id, &480);
+
+    let batch = s.vault.get_shares(&owner_id, &1666359075);
+    assert_eq!(batch.curr_s, 820);
+    assert_eq!(batch.init_s, 820);
+    assert_eq!(batch.deposit, 980);
+}
+
+#[test]
+fn test_wrapped_deposit_batch() {
+    let s = Setup::new();
+    let (_user1, user1_id) = s.user(0);
+    let (_user2, user2_id) = s.user(0);
+    let admin_id = Identifier::Account(s.admin.clone());
+
+    let wrapper_id = create_token(&s.e, &s.token_admin, 2);
+    let wrapper = token::Client::new(&s.e, &wrapper_id);
+
+    let converter_contract_id = s.e.register_contract(
+        &BytesN::from_array(&s.e, &[8; 32]),
+        converter::MockConverter,
+    );
+    let converter_id = Identifier::Contract(converter_contract_id.clone());
+    converter::MockConverterClient::new(&s.e, &converter_contract_id).init(&s.token_id, &2);
+    s.token
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &converter_id, &1000);
+
+    s.vault.with_source_account(&s.admin).set_deptok(
+        &invoker_auth(),
+        &wrapper_id,
+        &converter_contract_id,
+    );
+
+    wrapper
+        .with_source_account(&s.token_admin)
+        .mint(&Signature::Invoker, &0, &admin_id, &150);
+    wrapper
+        .with_source_account(&s.admin)
+        .approve(&Signature::Invoker, &0, &s.vault_id, &150);
+
+    s.vault.with_source_account(&s.admin).dep_batch(
+        &invoker_auth(),
+        &vec![
+            &s.e,
+            vault::DepEntry(user1_id.clone(), 100),
+            vault::DepEntry(user2_id.clone(), 50),
+        ],
+    );
+
+    // the 150 wrapper tokens are converted at once into 300 of the vault's token, split pro rata
+    assert_eq!(wrapper.balance(&admin_id), 0);
+    assert_eq!(wrapper.balance(&converter_id), 150);
+    assert_eq!(s.token.balance(&s.vault_id), 300);
+    assert_eq!(s.vault.shares(&user1_id), 200);
+    assert_eq!(s.vault.shares(&user2_id), 100);
+    assert_eq!(s.vault.tot_assets(), 300);
+}
+
+#[test]
+fn test_simulate_withdraw_without_shares() {
+    let s = Setup::new();
+    let (user1, user1_id) = s.user(500);
+    let (_user2, user2_id) = s.user(0);
+
+    s.vault
+        .with_source_account(&user1)
+        .deposit(&invoker_auth(), &user1_id, &500);
+    set_ts(&s.e, 1666359175);
+    s.vault
+        .with_source_account(&user1)
+        .withdraw(&invoker_auth(), &user1_id);
+
+    // assets are left with no shares outstanding, they aren't anyone's payout
+    s.yield_fees(50);
+    assert_eq!(s.vault.tot_assets(), 50);
+
+    for id in [user1_id, user2_id] {
+        let sim = s.vault.sim_withd(&id);
+        assert_eq!(sim.deposits, 0);
+        assert_eq!(sim.fees, 0);
+        assert_eq!(sim.payout, 0);
+        assert_eq!(sim.recall, 0);
+        assert!(!sim.instant);
+        assert_eq!(sim.blocked, vault::VaultError::InvalidAmount as u32);
+    }
+}
+
+#[test]
+fn test_fee_withdraw_keeps_request_on_loss() {
+    let s = Setup::new();
+    let (user, user_id) = s.user(1000);
+    let vault = s.vault.with_source_account(&user);
+
+    s.vault
+        .with_source_account(&s.admin)
+        .set_cooldn(&invoker_auth(), &1000);
+
+    vault.deposit(&invoker_auth(), &user_id, &500);
+    vault.req_withd(&invoker_auth(), &user_id);
+
+    // there are no fees to take out during a loss, the call pays nothing
+    s.token
+        .with_source_account(&s.token_admin)
+        .burn(&Signature::Invoker, &0, &s.vault_id, &100);
+    set_ts(&s.e, 1666360075);
+    vault.fee_withd(&invoker_auth(), &user_id, &1666359075, &500);
+    assert_eq!(s.token.balance(&user_id), 500);
+    assert_eq!(s.vault.shares(&user_id), 500);
+
+    // the matured request is still there for the withdrawal
+    vault.withdraw(&invoker_auth(), &user_id);
+    assert_eq!(s.token.balance(&user_id), 900);
+}